@@ -3,7 +3,7 @@ use std::{
     io::{BufRead, BufReader, BufWriter, Write},
     net::TcpStream,
     thread::sleep,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use aes::Aes128;
@@ -20,16 +20,28 @@ use rsa::{
     pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey},
     pkcs1v15::SigningKey,
     pkcs8::{DecodePrivateKey, DecodePublicKey},
-    sha2::Sha256,
+    sha2::{Digest, Sha256},
     signature::{SignatureEncoding, SignerMut},
     Oaep, RsaPrivateKey, RsaPublicKey,
 };
 
 const SERVER_PREFIX: &str = "http://localhost:3000";
 
-const EXAMPLE_SENSOR: &str = "{\"name\":\"example_sensor\",\"fields\":[\"x_accel\",\"y_accel\",\"z_accel\"],\"field_types\":[\"Integer\",\"Integer\",\"Integer\"],\"key\":[253,164,146,234,150,173,182,68,139,195,116,215,26,83,82,82],\"interval\":10,\"ccm_data\":{\"_direction_bit\":false,\"iv\":[0,1,2,3,4,5,6,7]}}";
-const MISSING_SENSOR: &str = "{\"name\":\"missing_sensor\",\"fields\":[\"x_accel\",\"y_accel\",\"z_accel\"],\"field_types\":[\"Integer\",\"Integer\",\"Integer\"],\"key\":[253,164,146,234,150,173,182,68,139,195,116,215,26,83,82,82],\"interval\":10,\"ccm_data\":{\"_direction_bit\":false,\"iv\":[0,1,2,3,4,5,6,7]}}";
-const BAD_SENSOR: &str = "{\"ae\":\"bad_sensor\",\"fields\":[\"x_accel\",\"y_accel\",\"z_accel\"],\"field_types\":[\"Integer\",\"Integer\",\"Integer\"],\"key\":[253,164,146,234,150,173,182,68,139,195,116,215,26,83,82,82],\"interval\":10,\"ccm_data\":{\"_direction_bit\":false,\"iv\":[0,1,2,3,4,5,6,7]}}";
+const SEED_SIZE: usize = 2048 / 8;
+const KEY_INTERVAL: u64 = 10;
+
+// key material of the example sensor, matches the sensor firmware
+const EXAMPLE_SEED: [u8; SEED_SIZE + 4] = [0; SEED_SIZE + 4];
+const EXAMPLE_INITIAL_KEY: [u8; 16] = [
+    253, 164, 146, 234, 150, 173, 182, 68, 139, 195, 116, 215, 26, 83, 82, 82,
+];
+
+fn sensor_json(name_field: &str, name: &str) -> String {
+    format!(
+        "{{\"{}\":\"{}\",\"fields\":[\"accel_x\",\"accel_y\",\"accel_z\"],\"field_types\":[\"Integer\",\"Integer\",\"Integer\"],\"key\":{:?},\"initial_key\":{:?},\"interval\":{},\"ccm_data\":{{\"_direction_bit\":false,\"iv\":[0,1,2,3,4,5,6,7]}}}}",
+        name_field, name, EXAMPLE_SEED, EXAMPLE_INITIAL_KEY, KEY_INTERVAL
+    )
+}
 
 fn main() {
     let args = Args::parse();
@@ -56,10 +68,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/register_sensor";
         sensor_action(
-            url,
-            EXAMPLE_SENSOR,
+            "POST",
+            "/register_sensor",
+            Some(&sensor_json("name", "example_sensor")),
             server_pub_key,
             "unauthorized_user",
             args.fail_challenge,
@@ -72,10 +84,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/register_sensor";
         sensor_action(
-            url,
-            EXAMPLE_SENSOR,
+            "POST",
+            "/register_sensor",
+            Some(&sensor_json("name", "example_sensor")),
             server_pub_key,
             "test_user",
             args.fail_challenge,
@@ -88,10 +100,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/deregister_sensor";
         sensor_action(
-            url,
-            EXAMPLE_SENSOR,
+            "DELETE",
+            "/sensors/example_sensor",
+            None,
             server_pub_key,
             "test_user",
             args.fail_challenge,
@@ -104,10 +116,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/register_sensor";
         sensor_action(
-            url,
-            BAD_SENSOR,
+            "POST",
+            "/register_sensor",
+            Some(&sensor_json("ae", "bad_sensor")),
             server_pub_key,
             "test_user",
             args.fail_challenge,
@@ -120,10 +132,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/deregister_sensor";
         sensor_action(
-            url,
-            BAD_SENSOR,
+            "POST",
+            "/deregister_sensor",
+            Some(&sensor_json("ae", "bad_sensor")),
             server_pub_key,
             "test_user",
             args.fail_challenge,
@@ -136,10 +148,10 @@ fn main() {
             server_public_key = Some(get_server_public_key(&client));
         }
         let server_pub_key = server_public_key.as_ref().unwrap();
-        let url = SERVER_PREFIX.to_string() + "/deregister_sensor";
         sensor_action(
-            url,
-            MISSING_SENSOR,
+            "DELETE",
+            "/sensors/missing_sensor",
+            None,
             server_pub_key,
             "test_user",
             args.fail_challenge,
@@ -166,7 +178,7 @@ impl CcmData {
         }
     }
 
-    fn _increment_counter(&mut self) {
+    fn increment_counter(&mut self) {
         self.counter += 1;
     }
 
@@ -232,12 +244,9 @@ fn test_data() {
     let stream = TcpStream::connect("127.0.0.1:8000").unwrap();
     let mut writer = BufWriter::new(stream);
 
-    let ccm_data = CcmData::new([0, 1, 2, 3, 4, 5, 6, 7]);
+    let mut ccm_data = CcmData::new([0, 1, 2, 3, 4, 5, 6, 7]);
 
-    let key = [
-        253, 164, 146, 234, 150, 173, 182, 68, 139, 195, 116, 215, 26, 83, 82, 82,
-    ];
-    let cipher = Aes128Ccm::new_from_slice(&key).unwrap();
+    let cipher = Aes128Ccm::new(&key_for_counter(ccm_data.counter).into());
 
     let ciphertext = cipher
         .encrypt(
@@ -250,8 +259,12 @@ fn test_data() {
 
     for line in reader.lines() {
         let line = line.unwrap();
-        writer.write(b">example_sensor<").unwrap();
-        writer.write(&ccm_data.counter.to_le_bytes()[..5]).unwrap();
+        let cipher = Aes128Ccm::new(&key_for_counter(ccm_data.counter).into());
+
+        writer.write_all(b">example_sensor<").unwrap();
+        writer
+            .write_all(&ccm_data.counter.to_le_bytes()[..5])
+            .unwrap();
 
         let ciphertext = cipher
             .encrypt((&ccm_data.generate_nonce()).into(), line.as_bytes())
@@ -259,44 +272,72 @@ fn test_data() {
 
         let len: u8 = ciphertext.len() as u8;
         let len = [len];
-        writer.write(&len).unwrap();
-        writer.write(&ciphertext[..]).unwrap();
+        writer.write_all(&len).unwrap();
+        writer.write_all(&ciphertext[..]).unwrap();
         writer.flush().unwrap();
+        ccm_data.increment_counter();
         sleep(Duration::from_millis(900));
     }
 }
 
+/// The key the firmware encrypts packet `counter` with: its initial key until
+/// the first rotation, a key derived from the seed after that.
+fn key_for_counter(counter: u64) -> [u8; 16] {
+    match (counter / KEY_INTERVAL) as u32 {
+        0 => EXAMPLE_INITIAL_KEY,
+        interval_counter => derive_key(&EXAMPLE_SEED, interval_counter),
+    }
+}
+
+/// Derives the sensor's key for an interval the same way the firmware does.
+fn derive_key(seed: &[u8], interval_counter: u32) -> [u8; 16] {
+    let mut seed = seed.to_vec();
+    seed[..4].copy_from_slice(&interval_counter.to_be_bytes());
+
+    let mut hasher = Sha256::new();
+    hasher.update(&seed);
+    hasher.finalize()[..16].try_into().unwrap()
+}
+
+/// Sends a signed request as `user`. A body is encrypted to the server key.
 fn sensor_action(
-    url: String,
-    body: &str,
+    method: &str,
+    path: &str,
+    body: Option<&str>,
     server_pub_key: &RsaPublicKey,
     user: &str,
     fail_challenge: bool,
 ) {
-    let (key_header, encrypted_body) = encrypt_body(body.as_bytes(), server_pub_key);
+    let (key_header, encrypted_body) = match body {
+        Some(body) => {
+            let (key_header, encrypted_body) = encrypt_body(body.as_bytes(), server_pub_key);
+            (Some(key_header), encrypted_body)
+        }
+        None => (None, Vec::new()),
+    };
 
     let (_pub_key, priv_key) = load_user_keys();
     let mut signing_key: SigningKey<Sha256> = priv_key.into();
-    let signature = sign_data(&encrypted_body, &mut signing_key);
 
     let client: Client = Client::new();
     let challenge = get_challenge(&client);
-    let challenge_signature = if fail_challenge {
-        let bad_challenge: [u8; 7] = [55, 55, 55, 55, 55, 55, 55];
-        sign_data(&bad_challenge, &mut signing_key)
+    let challenge = if fail_challenge {
+        vec![55, 55, 55, 55, 55, 55, 55]
     } else {
-        sign_data(&challenge, &mut signing_key)
+        challenge
     };
+    let (timestamp, signature) =
+        sign_request(method, path, &challenge, &encrypted_body, &mut signing_key);
 
-    let response = client
-        .post(url)
+    let mut request = client
+        .request(method.parse().unwrap(), SERVER_PREFIX.to_string() + path)
         .header("user", user)
-        .header("signature", BASE64_STANDARD.encode(signature))
-        .header("key", BASE64_STANDARD.encode(key_header))
-        .header("challenge", BASE64_STANDARD.encode(challenge_signature))
-        .body(encrypted_body)
-        .send()
-        .unwrap();
+        .header("timestamp", timestamp)
+        .header("signature", signature);
+    if let Some(key_header) = key_header {
+        request = request.header("key", BASE64_STANDARD.encode(key_header));
+    }
+    let response = request.body(encrypted_body).send().unwrap();
 
     println!(
         "Server Response: {:?}",
@@ -304,14 +345,44 @@ fn sensor_action(
     );
 }
 
+/// Signs the request envelope the server verifies: method, path, challenge,
+/// timestamp and body hash on separate lines. Returns the values of the
+/// `timestamp` and `signature` headers.
+fn sign_request(
+    method: &str,
+    path_and_query: &str,
+    challenge: &[u8],
+    body: &[u8],
+    signing_key: &mut SigningKey<Sha256>,
+) -> (String, String) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let body_hash: String = Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let envelope = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        method,
+        path_and_query,
+        BASE64_STANDARD.encode(challenge),
+        timestamp,
+        body_hash
+    );
+
+    let signature = sign_data(envelope.as_bytes(), signing_key);
+    (timestamp.to_string(), BASE64_STANDARD.encode(signature))
+}
+
 fn get_challenge(client: &Client) -> Vec<u8> {
     let response = client
         .get(SERVER_PREFIX.to_string() + "/challenge/test_user")
         .send()
         .unwrap();
 
-    let challenge = response.bytes().unwrap().to_vec();
-    challenge
+    response.bytes().unwrap().to_vec()
 }
 
 fn sign_data(data: &[u8], signing_key: &mut SigningKey<Sha256>) -> Box<[u8]> {
@@ -357,7 +428,5 @@ fn get_server_public_key(client: &Client) -> RsaPublicKey {
         .text()
         .unwrap();
 
-    let server_public_key = RsaPublicKey::from_pkcs1_pem(&server_string_key).unwrap();
-
-    server_public_key
+    RsaPublicKey::from_pkcs1_pem(&server_string_key).unwrap()
 }
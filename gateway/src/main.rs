@@ -0,0 +1,714 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread::sleep,
+    time::Duration,
+};
+
+use clap::Parser;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+use serialport::SerialPort;
+
+const FRAME_START: u8 = b'>';
+const NAME_END: u8 = b'<';
+const MAX_NAME_LEN: usize = 64;
+
+// the firmware writes a u32 counter
+const SENSOR_COUNTER_SIZE: usize = 4;
+
+// magic/version byte of the server's version 1 frame format
+const FRAME_V1: u8 = 0xA1;
+// asks the server to answer the frame with an ack
+const FLAG_ACK_REQUESTED: u8 = 0x01;
+
+// ack statuses the gateway acts on, the others reject the frame for good
+const ACK_ACCEPTED: u8 = 0;
+const ACK_REPLAYED: u8 = 3;
+const ACK_STORAGE_FAILURE: u8 = 5;
+
+const READ_BUF_SIZE: usize = 256;
+
+fn main() {
+    let args = Args::parse();
+    let retry_delay = Duration::from_secs(args.retry_delay);
+
+    let tls = match &args.tls_ca {
+        Some(ca) => {
+            let tls = tls_config(ca, args.tls_cert.as_deref(), args.tls_key.as_deref())
+                .and_then(|config| Ok((config, server_name(&args)?)));
+            match tls {
+                Ok(tls) => Some(tls),
+                Err(e) => {
+                    eprintln!("Failed to set up TLS: {}", e);
+                    process::exit(2);
+                }
+            }
+        }
+        None => None,
+    };
+    let connector = Connector {
+        address: args.server.clone(),
+        tls,
+        ack_timeout: args
+            .wait_for_ack
+            .then(|| Duration::from_secs(args.ack_timeout)),
+    };
+
+    let mut server: Option<Box<dyn Stream>> = None;
+
+    loop {
+        let mut device = match open_device(&args) {
+            Ok(device) => device,
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", args.device, e);
+                sleep(retry_delay);
+                continue;
+            }
+        };
+        println!("Opened {} at {} baud", args.device, args.baud);
+
+        let e = bridge(&mut *device, &mut server, &connector, &args);
+        eprintln!("Lost connection to {}: {}", args.device, e);
+        sleep(retry_delay);
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// serial device (or pty) the sensor is attached to
+    #[arg(short, long)]
+    device: String,
+
+    /// baud rate of the serial device
+    #[arg(short, long, default_value_t = 115200)]
+    baud: u32,
+
+    /// address of the server's data listener
+    #[arg(short, long, default_value = "127.0.0.1:8000")]
+    server: String,
+
+    /// seconds to wait before reconnecting to the device or server
+    #[arg(short, long, default_value_t = 2)]
+    retry_delay: u64,
+
+    /// PEM CA certificates to verify the server with, enables TLS
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate to present to the server
+    #[arg(long, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// name the server certificate is checked against [default: host of --server]
+    #[arg(long, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// keep each frame until the server acknowledges it, resending it after
+    /// a reconnect
+    #[arg(long)]
+    wait_for_ack: bool,
+
+    /// seconds to wait for an ack before reconnecting
+    #[arg(long, default_value_t = 5, requires = "wait_for_ack")]
+    ack_timeout: u64,
+}
+
+/// Connection to the server, plain or TLS.
+trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+/// How to reach the server's data listener.
+struct Connector {
+    address: String,
+    tls: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+    /// How long to wait for an ack, if acks are requested.
+    ack_timeout: Option<Duration>,
+}
+
+impl Connector {
+    fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(self.ack_timeout)?;
+
+        match &self.tls {
+            Some((config, server_name)) => {
+                let connection = ClientConnection::new(config.clone(), server_name.clone())
+                    .map_err(io::Error::other)?;
+                Ok(Box::new(StreamOwned::new(connection, stream)))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+}
+
+fn tls_config(ca: &Path, cert: Option<&Path>, key: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca)? {
+        roots.add(cert).map_err(io::Error::other)?;
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots);
+    let config = match (cert, key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(io::Error::other)?,
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no private key in {}", path.display()),
+        )
+    })
+}
+
+fn server_name(args: &Args) -> io::Result<ServerName<'static>> {
+    let name = match &args.tls_server_name {
+        Some(name) => name.as_str(),
+        // strip the port, and the brackets of an IPv6 address
+        None => args
+            .server
+            .rsplit_once(':')
+            .map_or(args.server.as_str(), |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+
+    ServerName::try_from(name.to_owned()).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid server name {}: {}", name, e),
+        )
+    })
+}
+
+fn open_device(args: &Args) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(&args.device, args.baud)
+        .timeout(Duration::from_secs(1))
+        .open()
+}
+
+/// Reads frames from the device and forwards them until the device fails.
+fn bridge(
+    device: &mut dyn SerialPort,
+    server: &mut Option<Box<dyn Stream>>,
+    connector: &Connector,
+    args: &Args,
+) -> io::Error {
+    let mut deframer = Deframer::new();
+    let mut read_buf = [0u8; READ_BUF_SIZE];
+
+    loop {
+        match device.read(&mut read_buf) {
+            Ok(0) => return io::Error::new(ErrorKind::UnexpectedEof, "device closed"),
+            Ok(n) => deframer.push(&read_buf[..n]),
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {
+                continue
+            }
+            Err(e) => return e,
+        }
+
+        while let Some(frame) = deframer.next_frame() {
+            forward(server, &frame, connector, args);
+        }
+
+        let skipped = deframer.take_skipped();
+        if skipped > 0 {
+            eprintln!("Skipped {} bytes while resynchronizing", skipped);
+        }
+    }
+}
+
+/// Writes a frame to the server, reconnecting until it succeeds. When
+/// waiting for acks, the frame is only done once the server answered it.
+fn forward(
+    server: &mut Option<Box<dyn Stream>>,
+    frame: &Frame,
+    connector: &Connector,
+    args: &Args,
+) {
+    let flags = if args.wait_for_ack {
+        FLAG_ACK_REQUESTED
+    } else {
+        0
+    };
+    let bytes = frame.encode(flags);
+
+    loop {
+        if server.is_none() {
+            match connector.connect() {
+                Ok(stream) => {
+                    println!("Connected to server {}", args.server);
+                    *server = Some(stream);
+                }
+                Err(e) => {
+                    eprintln!("Failed to connect to server {}: {}", args.server, e);
+                    sleep(Duration::from_secs(args.retry_delay));
+                    continue;
+                }
+            }
+        }
+
+        let stream = server.as_mut().unwrap();
+        if let Err(e) = stream.write_all(&bytes).and_then(|_| stream.flush()) {
+            eprintln!("Lost connection to server {}: {}", args.server, e);
+            *server = None;
+            continue;
+        }
+        if !args.wait_for_ack {
+            return;
+        }
+
+        match read_ack(stream, frame.counter) {
+            Ok(ACK_ACCEPTED) => return,
+            Ok(ACK_REPLAYED) => {
+                // usually an earlier copy whose ack got lost
+                println!("Server already received frame {}", frame.counter);
+                return;
+            }
+            Ok(ACK_STORAGE_FAILURE) => {
+                eprintln!("Server failed to store frame {}, resending", frame.counter);
+                sleep(Duration::from_secs(args.retry_delay));
+            }
+            Ok(status) => {
+                eprintln!(
+                    "Server rejected frame {} from {} with status {}",
+                    frame.counter, frame.name, status
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("No ack from server {}: {}", args.server, e);
+                *server = None;
+            }
+        }
+    }
+}
+
+/// Reads acks until the one for `counter` arrives and returns its status.
+///
+/// Acks are laid out as the magic/version byte, the status, the counter
+/// width, the counter and a CRC-16 of the preceding bytes.
+fn read_ack(stream: &mut dyn Read, counter: u32) -> io::Result<u8> {
+    loop {
+        let mut header = [0u8; 3];
+        stream.read_exact(&mut header)?;
+        let [magic, status, width] = header;
+        let width = width as usize;
+        if magic != FRAME_V1 || !(1..=8).contains(&width) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "malformed ack"));
+        }
+
+        let mut rest = [0u8; 10];
+        stream.read_exact(&mut rest[..width + 2])?;
+        let mut raw = header.to_vec();
+        raw.extend(&rest[..width]);
+        if crc16(&raw).to_le_bytes() != rest[width..width + 2] {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "ack checksum mismatch",
+            ));
+        }
+
+        let mut acked = [0u8; 8];
+        acked[..width].copy_from_slice(&rest[..width]);
+        if u64::from_le_bytes(acked) == counter as u64 {
+            return Ok(status);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    name: String,
+    counter: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Encodes the frame using the server's version 1 frame format.
+    fn encode(&self, flags: u8) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.name.len() + self.payload.len() + 12);
+        bytes.push(FRAME_V1);
+        bytes.push(flags);
+        bytes.push(self.name.len() as u8);
+        bytes.extend(self.name.as_bytes());
+        bytes.push(SENSOR_COUNTER_SIZE as u8);
+        bytes.extend(self.counter.to_le_bytes());
+        bytes.extend((self.payload.len() as u16).to_le_bytes());
+        bytes.extend(&self.payload);
+        bytes.extend(crc16(&bytes).to_le_bytes());
+
+        bytes
+    }
+}
+
+/// CRC-16/CCITT-FALSE, as checked by the server.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Splits the raw byte stream written by the sensor firmware into frames.
+///
+/// Bytes that do not belong to a frame are skipped until the next `>` marker,
+/// so the gateway can be attached in the middle of a transmission.
+struct Deframer {
+    buf: Vec<u8>,
+    skipped: usize,
+}
+
+impl Deframer {
+    fn new() -> Self {
+        Deframer {
+            buf: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes discarded since the last call.
+    fn take_skipped(&mut self) -> usize {
+        std::mem::take(&mut self.skipped)
+    }
+
+    fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            // resynchronize on the frame start marker
+            let Some(start) = self.buf.iter().position(|b| *b == FRAME_START) else {
+                self.skipped += self.buf.len();
+                self.buf.clear();
+                return None;
+            };
+            self.skipped += start;
+            self.buf.drain(..start);
+
+            // find end of sensor name
+            let search_len = self.buf.len().min(MAX_NAME_LEN + 2);
+            let Some(name_end) = self.buf[1..search_len].iter().position(|b| *b == NAME_END) else {
+                if self.buf.len() > MAX_NAME_LEN + 1 {
+                    self.discard_marker();
+                    continue;
+                }
+                return None;
+            };
+            let name_end = name_end + 1;
+
+            let Ok(name) = std::str::from_utf8(&self.buf[1..name_end]) else {
+                self.discard_marker();
+                continue;
+            };
+            if name.is_empty() {
+                self.discard_marker();
+                continue;
+            }
+
+            // counter and length
+            let counter_start = name_end + 1;
+            let len_index = counter_start + SENSOR_COUNTER_SIZE;
+            if self.buf.len() <= len_index {
+                return None;
+            }
+            let payload_end = len_index + 1 + self.buf[len_index] as usize;
+            if self.buf.len() < payload_end {
+                return None;
+            }
+
+            let counter =
+                u32::from_le_bytes(self.buf[counter_start..len_index].try_into().unwrap());
+            let frame = Frame {
+                name: name.to_owned(),
+                counter,
+                payload: self.buf[len_index + 1..payload_end].to_vec(),
+            };
+            self.buf.drain(..payload_end);
+
+            return Some(frame);
+        }
+    }
+
+    fn discard_marker(&mut self) {
+        self.buf.remove(0);
+        self.skipped += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{server::WebPkiClientVerifier, ServerConfig, ServerConnection};
+    use std::{fs, io::Read, net::TcpListener, thread};
+
+    fn sensor_bytes(name: &str, counter: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(FRAME_START);
+        bytes.extend(name.as_bytes());
+        bytes.push(NAME_END);
+        bytes.extend(counter.to_le_bytes());
+        bytes.push(payload.len() as u8);
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn single_frame() {
+        let mut deframer = Deframer::new();
+        deframer.push(&sensor_bytes("example_sensor", 7, b"ciphertext"));
+
+        let frame = deframer.next_frame().unwrap();
+        assert_eq!(frame.name, "example_sensor");
+        assert_eq!(frame.counter, 7);
+        assert_eq!(frame.payload, b"ciphertext");
+        assert_eq!(deframer.next_frame(), None);
+        assert_eq!(deframer.take_skipped(), 0);
+    }
+
+    #[test]
+    fn split_frame() {
+        let bytes = sensor_bytes("example_sensor", 1, b"ciphertext");
+        let mut deframer = Deframer::new();
+
+        for byte in &bytes[..bytes.len() - 1] {
+            deframer.push(&[*byte]);
+            assert_eq!(deframer.next_frame(), None);
+        }
+        deframer.push(&bytes[bytes.len() - 1..]);
+
+        assert_eq!(deframer.next_frame().unwrap().payload, b"ciphertext");
+    }
+
+    #[test]
+    fn resynchronize_after_garbage() {
+        let mut deframer = Deframer::new();
+        deframer.push(b"garbage");
+        deframer.push(&[FRAME_START; 1]);
+        deframer.push(&[b'x'; MAX_NAME_LEN + 4]);
+        deframer.push(&sensor_bytes("example_sensor", 2, b"data"));
+
+        let frame = deframer.next_frame().unwrap();
+        assert_eq!(frame.counter, 2);
+        assert_eq!(deframer.take_skipped(), 7 + 1 + MAX_NAME_LEN + 4);
+    }
+
+    #[test]
+    fn encode_v1_frame() {
+        let frame = Frame {
+            name: "s".to_owned(),
+            counter: 0x01020304,
+            payload: vec![9, 9],
+        };
+
+        let bytes = frame.encode(FLAG_ACK_REQUESTED);
+        assert_eq!(
+            &bytes[..bytes.len() - 2],
+            &[FRAME_V1, 1, 1, b's', 4, 4, 3, 2, 1, 2, 0, 9, 9]
+        );
+        assert_eq!(
+            crc16(&bytes[..bytes.len() - 2]).to_le_bytes(),
+            bytes[bytes.len() - 2..]
+        );
+    }
+
+    fn ack_bytes(counter: u32, status: u8) -> Vec<u8> {
+        let mut bytes = vec![FRAME_V1, status, 4];
+        bytes.extend(counter.to_le_bytes());
+        bytes.extend(crc16(&bytes).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn read_acks() {
+        let mut bytes = ack_bytes(1, ACK_ACCEPTED);
+        bytes.extend(ack_bytes(2, ACK_REPLAYED));
+        assert_eq!(read_ack(&mut &bytes[..], 2).unwrap(), ACK_REPLAYED);
+
+        let mut corrupted = ack_bytes(2, ACK_ACCEPTED);
+        corrupted[1] = ACK_REPLAYED;
+        let e = read_ack(&mut &corrupted[..], 2).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn resends_until_acked() {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let frame = Frame {
+            name: "example_sensor".to_owned(),
+            counter: 9,
+            payload: b"ciphertext".to_vec(),
+        };
+        let expected = frame.encode(FLAG_ACK_REQUESTED);
+
+        let server = thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut read_frame = |stream: &mut std::net::TcpStream| {
+                let mut bytes = vec![0; expected.len()];
+                stream.read_exact(&mut bytes).unwrap();
+                received.push(bytes);
+            };
+
+            // no ack, the gateway gives up on the connection
+            let (mut stream, _) = listener.accept().unwrap();
+            read_frame(&mut stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            read_frame(&mut stream);
+            stream
+                .write_all(&ack_bytes(9, ACK_STORAGE_FAILURE))
+                .unwrap();
+            read_frame(&mut stream);
+            stream.write_all(&ack_bytes(8, ACK_ACCEPTED)).unwrap();
+            stream.write_all(&ack_bytes(9, ACK_ACCEPTED)).unwrap();
+
+            received
+        });
+
+        let args = Args::parse_from([
+            "gateway",
+            "--device",
+            "unused",
+            "--server",
+            &address.to_string(),
+            "--retry-delay",
+            "0",
+            "--wait-for-ack",
+            "--ack-timeout",
+            "1",
+        ]);
+        let connector = Connector {
+            address: args.server.clone(),
+            tls: None,
+            ack_timeout: Some(Duration::from_secs(args.ack_timeout)),
+        };
+        let mut stream = None;
+        forward(&mut stream, &frame, &connector, &args);
+
+        let received = server.join().unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received
+            .iter()
+            .all(|bytes| *bytes == frame.encode(FLAG_ACK_REQUESTED)));
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn forwards_over_mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("gateway-tls-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        for name in ["server", "gateway"] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["localhost".to_owned()])
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+            fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .unwrap();
+        let server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs(&dir.join("server.pem")).unwrap(),
+                load_key(&dir.join("server.key")).unwrap(),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut stream = StreamOwned::new(connection, stream);
+            let mut received = Vec::new();
+            let _ = stream.read_to_end(&mut received);
+            received
+        });
+
+        let frame = Frame {
+            name: "example_sensor".to_owned(),
+            counter: 3,
+            payload: b"ciphertext".to_vec(),
+        };
+        let connector = Connector {
+            address: address.to_string(),
+            tls: Some((
+                tls_config(
+                    &dir.join("ca.pem"),
+                    Some(&dir.join("gateway.pem")),
+                    Some(&dir.join("gateway.key")),
+                )
+                .unwrap(),
+                ServerName::try_from("localhost").unwrap(),
+            )),
+            ack_timeout: None,
+        };
+        let mut stream = connector.connect().unwrap();
+        stream.write_all(&frame.encode(0)).unwrap();
+        stream.flush().unwrap();
+        drop(stream);
+
+        assert_eq!(server.join().unwrap(), frame.encode(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+//! Tamper-evident audit log of security relevant actions.
+//!
+//! Entries are appended to a JSON lines file. Every entry carries the hash of
+//! the entry before it and a SHA-256 hash over its own contents, so editing,
+//! dropping or reordering entries breaks the chain and is caught by
+//! [`AuditLog::verify`]. Someone able to rewrite the whole file could forge a
+//! new chain, so keep a copy of the latest hash elsewhere if that matters.
+//!
+//! Reading and writing the file blocks, so every operation runs on the
+//! blocking thread pool.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{event, Level};
+
+use crate::storage::now_millis;
+
+/// Previous hash of the first entry.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    IssueChallenge,
+    AuthenticationFailure,
+    Login,
+    RegisterSensor,
+    DeregisterSensor,
+    UpdateSensor,
+    RotateServerKey,
+    AddUser,
+    UpdateUser,
+    RemoveUser,
+    RotateUserKey,
+    ListUsers,
+    ReadAuditLog,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            AuditAction::IssueChallenge => "request a challenge",
+            AuditAction::AuthenticationFailure => "authenticate",
+            AuditAction::Login => "log in",
+            AuditAction::RegisterSensor => "register sensors",
+            AuditAction::DeregisterSensor => "deregister sensors",
+            AuditAction::UpdateSensor => "update sensors",
+            AuditAction::RotateServerKey => "rotate the server key",
+            AuditAction::AddUser => "add users",
+            AuditAction::UpdateUser => "update users",
+            AuditAction::RemoveUser => "remove users",
+            AuditAction::RotateUserKey => "rotate user keys",
+            AuditAction::ListUsers => "list users",
+            AuditAction::ReadAuditLog => "read the audit log",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Authenticated user, or the claimed user for failed attempts.
+    pub actor: Option<String>,
+    pub address: IpAddr,
+    pub action: AuditAction,
+    /// Sensor or user the action was applied to.
+    pub target: Option<String>,
+    /// HTTP status code the request was answered with.
+    pub status: u16,
+    pub previous_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Hash over every field but the hash itself.
+    fn compute_hash(&self) -> String {
+        let contents = serde_json::to_vec(&(
+            self.sequence,
+            self.timestamp,
+            &self.actor,
+            self.address,
+            self.action,
+            &self.target,
+            self.status,
+            &self.previous_hash,
+        ))
+        .unwrap();
+
+        Sha256::digest(contents)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// One action to append to the log.
+pub struct AuditEvent<'a> {
+    pub actor: Option<&'a str>,
+    pub address: IpAddr,
+    pub action: AuditAction,
+    pub target: Option<&'a str>,
+    pub status: u16,
+}
+
+#[derive(Debug)]
+pub enum ChainError {
+    Io(io::Error),
+    /// A line (counted from 1) is not a valid entry.
+    Malformed {
+        line: usize,
+    },
+    /// An entry doesn't directly follow the one before it.
+    OutOfSequence {
+        sequence: u64,
+    },
+    /// An entry doesn't link to the hash of the one before it.
+    BrokenLink {
+        sequence: u64,
+    },
+    /// An entry's contents don't match its hash.
+    BadHash {
+        sequence: u64,
+    },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Io(e) => write!(f, "failed to read audit log: {}", e),
+            ChainError::Malformed { line } => write!(f, "line {} is not a valid entry", line),
+            ChainError::OutOfSequence { sequence } => {
+                write!(f, "entry {} is out of sequence", sequence)
+            }
+            ChainError::BrokenLink { sequence } => {
+                write!(f, "entry {} doesn't link to the previous entry", sequence)
+            }
+            ChainError::BadHash { sequence } => {
+                write!(f, "entry {} doesn't match its hash", sequence)
+            }
+        }
+    }
+}
+
+struct ChainHead {
+    file: File,
+    /// Length of the file up to the end of the last complete entry.
+    len: u64,
+    next_sequence: u64,
+    last_hash: String,
+}
+
+impl ChainHead {
+    fn append(&mut self, event: OwnedEvent) {
+        let mut entry = AuditEntry {
+            sequence: self.next_sequence,
+            timestamp: now_millis(),
+            actor: event.actor,
+            address: event.address,
+            action: event.action,
+            target: event.target,
+            status: event.status,
+            previous_hash: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.compute_hash();
+
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            event!(Level::ERROR, "Failed to write audit entry: {}", e);
+            // a torn line would break the chain for good
+            if let Err(e) = self.file.set_len(self.len) {
+                event!(Level::ERROR, "Failed to cut off partial audit entry: {}", e);
+            }
+            return;
+        }
+
+        self.len += line.len() as u64;
+        self.next_sequence += 1;
+        self.last_hash = entry.hash;
+    }
+}
+
+/// [`AuditEvent`] moved to the blocking thread pool.
+struct OwnedEvent {
+    actor: Option<String>,
+    address: IpAddr,
+    action: AuditAction,
+    target: Option<String>,
+    status: u16,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    head: Arc<Mutex<ChainHead>>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, continuing its chain if it exists.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = verify_chain(&contents) {
+            // keep appending, the broken spot stays detectable
+            event!(
+                Level::ERROR,
+                "Audit log {} is corrupt: {}",
+                path.display(),
+                e
+            );
+        }
+
+        let last = contents
+            .lines()
+            .rev()
+            .find_map(|line| serde_json::from_str::<AuditEntry>(line).ok());
+        let (next_sequence, last_hash) = match last {
+            Some(last) => (last.sequence + 1, last.hash),
+            None => (0, GENESIS_HASH.to_owned()),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+
+        Ok(AuditLog {
+            path,
+            head: Arc::new(Mutex::new(ChainHead {
+                file,
+                len,
+                next_sequence,
+                last_hash,
+            })),
+        })
+    }
+
+    /// Appends `event` to the chain. Failures are logged, an action is never
+    /// refused because it couldn't be audited.
+    pub async fn record(&self, event: AuditEvent<'_>) {
+        let event = OwnedEvent {
+            actor: event.actor.map(str::to_owned),
+            address: event.address,
+            action: event.action,
+            target: event.target.map(str::to_owned),
+            status: event.status,
+        };
+        let head = self.head.clone();
+        let appended = tokio::task::spawn_blocking(move || head.lock().unwrap().append(event));
+        if let Err(e) = appended.await {
+            event!(Level::ERROR, "Failed to write audit entry: {}", e);
+        }
+    }
+
+    /// Up to `limit` entries with a sequence number greater than `after`.
+    pub async fn entries(&self, after: Option<u64>, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let path = self.path.clone();
+        let contents = tokio::task::spawn_blocking(move || fs::read_to_string(path))
+            .await
+            .map_err(io::Error::other)??;
+
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(|entry| after.is_none_or(|after| entry.sequence > after))
+            .take(limit)
+            .collect())
+    }
+
+    /// Checks the whole chain, returning the number of entries.
+    pub async fn verify(&self) -> Result<u64, ChainError> {
+        let path = self.path.clone();
+        let head = self.head.clone();
+        tokio::task::spawn_blocking(move || {
+            // hold the head so no entry is half written while reading
+            let _head = head.lock().unwrap();
+            let contents = fs::read_to_string(path).map_err(ChainError::Io)?;
+
+            verify_chain(&contents)
+        })
+        .await
+        .map_err(|e| ChainError::Io(io::Error::other(e)))?
+    }
+}
+
+fn verify_chain(contents: &str) -> Result<u64, ChainError> {
+    let mut previous_hash = GENESIS_HASH.to_owned();
+    let mut count = 0;
+
+    for (i, line) in contents.lines().enumerate() {
+        let Ok(entry) = serde_json::from_str::<AuditEntry>(line) else {
+            return Err(ChainError::Malformed { line: i + 1 });
+        };
+
+        if entry.sequence != count {
+            return Err(ChainError::OutOfSequence {
+                sequence: entry.sequence,
+            });
+        }
+        if entry.previous_hash != previous_hash {
+            return Err(ChainError::BrokenLink {
+                sequence: entry.sequence,
+            });
+        }
+        if entry.hash != entry.compute_hash() {
+            return Err(ChainError::BadHash {
+                sequence: entry.sequence,
+            });
+        }
+
+        previous_hash = entry.hash;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    async fn record(log: &AuditLog, actor: &str, target: &str) {
+        log.record(AuditEvent {
+            actor: Some(actor),
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            action: AuditAction::RegisterSensor,
+            target: Some(target),
+            status: 200,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn chain_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "alice", "a").await;
+        record(&log, "alice", "b").await;
+
+        let log = AuditLog::open(&path).unwrap();
+        record(&log, "bob", "c").await;
+        assert_eq!(log.verify().await.unwrap(), 3);
+
+        let entries = log.entries(Some(0), 10).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].sequence, 2);
+        assert_eq!(entries[1].actor.as_deref(), Some("bob"));
+        assert_eq!(entries[1].previous_hash, entries[0].hash);
+    }
+
+    #[tokio::test]
+    async fn tampering_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        for target in ["a", "b", "c"] {
+            record(&log, "alice", target).await;
+        }
+        let original = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+
+        // edited entry
+        fs::write(&path, original.replacen("\"alice\"", "\"bob\"", 1)).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(ChainError::BadHash { sequence: 0 })
+        ));
+
+        // dropped entry
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(ChainError::OutOfSequence { sequence: 2 })
+        ));
+
+        // entry with a recomputed hash still breaks the link to the next one
+        let mut entry: AuditEntry = serde_json::from_str(lines[1]).unwrap();
+        entry.target = Some("x".to_owned());
+        entry.hash = entry.compute_hash();
+        let forged = serde_json::to_string(&entry).unwrap();
+        fs::write(&path, format!("{}\n{}\n{}\n", lines[0], forged, lines[2])).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(ChainError::BrokenLink { sequence: 2 })
+        ));
+
+        fs::write(&path, format!("{}\nnot json\n", lines[0])).unwrap();
+        assert!(matches!(
+            log.verify().await,
+            Err(ChainError::Malformed { line: 2 })
+        ));
+    }
+}
@@ -0,0 +1,701 @@
+//! Server settings.
+//!
+//! Settings are layered: built-in defaults, then the TOML config file, then
+//! `PROJECT_SERVER_*` environment variables and finally command line flags.
+//! Every section and key of the file is optional:
+//!
+//! ```toml
+//! log_level = "info,project_server=debug"
+//!
+//! [listen]
+//! http = "0.0.0.0:3000"
+//! data = "0.0.0.0:8000"
+//! # Prometheus metrics, off unless set. The endpoint is unauthenticated and
+//! # labels series with sensor names, so keep it on loopback or a private
+//! # monitoring network.
+//! metrics = "127.0.0.1:9100"
+//!
+//! [paths]
+//! users = "authorized_users"
+//! data = "data"
+//! # server_key and master_key default to files in the data directory
+//! server_key = "/etc/project_server/server_key.pem"
+//!
+//! [auth]
+//! challenge_ttl_secs = 60
+//!
+//! [shutdown]
+//! # seconds open connections get to finish after SIGINT or SIGTERM
+//! deadline_secs = 10
+//!
+//! [tls]
+//! cert = "/etc/project_server/cert.pem"
+//! key = "/etc/project_server/key.pem"
+//! # gateways must present a certificate issued by this CA
+//! client_ca = "/etc/project_server/gateways.pem"
+//! ```
+
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    frame, http_server, identity, session, storage, tcp_server, users, DEFAULT_SHUTDOWN_DEADLINE,
+};
+
+/// Loaded when no config file is given, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+const MIN_KEY_SIZE: usize = 2048;
+const MAX_KEY_SIZE: usize = 8192;
+
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML config file [default: server.toml if it exists]
+    #[arg(short, long, env = "PROJECT_SERVER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// print the effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// address of the HTTP API
+    #[arg(long, env = "PROJECT_SERVER_HTTP_ADDR")]
+    http_addr: Option<SocketAddr>,
+
+    /// address sensors send their data to
+    #[arg(long, env = "PROJECT_SERVER_DATA_ADDR")]
+    data_addr: Option<SocketAddr>,
+
+    /// address of the unauthenticated Prometheus metrics endpoint
+    #[arg(long, env = "PROJECT_SERVER_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// tracing filter directives, e.g. "info,project_server=debug"
+    #[arg(long, env = "PROJECT_SERVER_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// directory of authorized user keys
+    #[arg(long, env = "PROJECT_SERVER_USERS_DIR")]
+    users_dir: Option<PathBuf>,
+
+    /// directory for readings, the sensor registry and server state
+    #[arg(long, env = "PROJECT_SERVER_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// PEM file of the server's RSA key
+    #[arg(long, env = "PROJECT_SERVER_SERVER_KEY")]
+    server_key: Option<PathBuf>,
+
+    /// file of the key encrypting sensor keys at rest
+    #[arg(long, env = "PROJECT_SERVER_MASTER_KEY")]
+    master_key: Option<PathBuf>,
+
+    /// size in bits of generated server keys
+    #[arg(long, env = "PROJECT_SERVER_KEY_SIZE")]
+    key_size: Option<usize>,
+
+    /// seconds a login challenge can be answered
+    #[arg(long, env = "PROJECT_SERVER_CHALLENGE_TTL")]
+    challenge_ttl: Option<u64>,
+
+    /// seconds a session token stays valid
+    #[arg(long, env = "PROJECT_SERVER_SESSION_TTL")]
+    session_ttl: Option<u64>,
+
+    /// maximum size in bytes of a reading log segment
+    #[arg(long, env = "PROJECT_SERVER_SEGMENT_SIZE")]
+    segment_size: Option<u64>,
+
+    /// seconds open connections get to finish when shutting down
+    #[arg(long, env = "PROJECT_SERVER_SHUTDOWN_DEADLINE")]
+    shutdown_deadline: Option<u64>,
+
+    /// PEM certificate chain, enables TLS
+    #[arg(long, env = "PROJECT_SERVER_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[arg(long, env = "PROJECT_SERVER_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates data port clients must be issued by
+    #[arg(long, env = "PROJECT_SERVER_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Tracing filter directives.
+    pub log_level: String,
+    pub listen: ListenConfig,
+    pub paths: PathConfig,
+    pub keys: KeyConfig,
+    pub auth: AuthConfig,
+    pub sensors: SensorConfig,
+    pub storage: StorageConfig,
+    pub shutdown: ShutdownConfig,
+    pub tls: TlsConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub http: SocketAddr,
+    pub data: SocketAddr,
+    /// Serves `/metrics` when set. Unauthenticated and it names every sensor
+    /// that sent data, so it gets its own listener instead of the API's.
+    pub metrics: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathConfig {
+    pub users: PathBuf,
+    pub data: PathBuf,
+    /// Defaults to `server_key.pem` in the data directory.
+    pub server_key: Option<PathBuf>,
+    /// Defaults to `master.key` in the data directory.
+    pub master_key: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Size in bits of generated server keys.
+    pub size: usize,
+    /// How long a rotated out server key is still accepted.
+    pub grace_period_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub challenge_ttl_secs: u64,
+    pub session_ttl_secs: u64,
+    /// How often the user directory is checked for changes.
+    pub user_reload_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorConfig {
+    /// Accept frames in the original unversioned layout.
+    pub accept_legacy_frames: bool,
+    /// Counter width in bytes of legacy frames. The firmware writes a 4 byte
+    /// `u32`.
+    pub legacy_counter_width: u8,
+    /// How long after its last frame a sensor counts as active.
+    pub active_window_secs: u64,
+    /// Bad frames a data connection may send in a burst before it is closed.
+    pub error_budget: u32,
+    /// Bad frames a second the budget refills by.
+    pub error_budget_per_sec: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Maximum size in bytes of a reading log segment.
+    pub segment_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long open connections get to finish after a shutdown signal.
+    pub deadline_secs: u64,
+}
+
+/// TLS is used once a certificate and key are configured.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Require data port clients to present a certificate issued by this CA.
+    pub client_ca: Option<PathBuf>,
+    /// Serve the HTTP API over TLS.
+    pub http: bool,
+    /// Accept only TLS on the data port. Sensors connecting without a
+    /// gateway can't do TLS, so turn this off for them.
+    pub data: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log_level: "debug,project_server=debug".to_owned(),
+            listen: ListenConfig::default(),
+            paths: PathConfig::default(),
+            keys: KeyConfig::default(),
+            auth: AuthConfig::default(),
+            sensors: SensorConfig::default(),
+            storage: StorageConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            http: "0.0.0.0:3000".parse().unwrap(),
+            data: "0.0.0.0:8000".parse().unwrap(),
+            metrics: None,
+        }
+    }
+}
+
+impl Default for PathConfig {
+    fn default() -> Self {
+        PathConfig {
+            users: PathBuf::from("authorized_users"),
+            data: PathBuf::from("data"),
+            server_key: None,
+            master_key: None,
+        }
+    }
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        KeyConfig {
+            size: identity::DEFAULT_KEY_SIZE,
+            grace_period_secs: identity::DEFAULT_GRACE_PERIOD.as_secs(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            challenge_ttl_secs: http_server::DEFAULT_CHALLENGE_TTL.as_secs(),
+            session_ttl_secs: session::DEFAULT_SESSION_TTL.as_secs(),
+            user_reload_secs: users::DEFAULT_RELOAD_INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            accept_legacy_frames: true,
+            legacy_counter_width: frame::DEFAULT_LEGACY_COUNTER_WIDTH,
+            active_window_secs: http_server::DEFAULT_ACTIVE_WINDOW.as_secs(),
+            error_budget: tcp_server::DEFAULT_ERROR_BUDGET.burst,
+            error_budget_per_sec: tcp_server::DEFAULT_ERROR_BUDGET.per_sec,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            client_ca: None,
+            http: true,
+            data: true,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            segment_size: storage::DEFAULT_SEGMENT_SIZE,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline_secs: DEFAULT_SHUTDOWN_DEADLINE.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "invalid config file {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the config file and `args`, which also
+    /// carry the environment overrides.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    fn apply(&mut self, args: &Args) {
+        fn set<T: Clone>(setting: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *setting = value.clone();
+            }
+        }
+
+        set(&mut self.listen.http, &args.http_addr);
+        set(&mut self.listen.data, &args.data_addr);
+        if args.metrics_addr.is_some() {
+            self.listen.metrics = args.metrics_addr;
+        }
+        set(&mut self.log_level, &args.log_level);
+        set(&mut self.paths.users, &args.users_dir);
+        set(&mut self.paths.data, &args.data_dir);
+        if args.server_key.is_some() {
+            self.paths.server_key = args.server_key.clone();
+        }
+        if args.master_key.is_some() {
+            self.paths.master_key = args.master_key.clone();
+        }
+        if args.tls_cert.is_some() {
+            self.tls.cert = args.tls_cert.clone();
+        }
+        if args.tls_key.is_some() {
+            self.tls.key = args.tls_key.clone();
+        }
+        if args.tls_client_ca.is_some() {
+            self.tls.client_ca = args.tls_client_ca.clone();
+        }
+        set(&mut self.keys.size, &args.key_size);
+        set(&mut self.auth.challenge_ttl_secs, &args.challenge_ttl);
+        set(&mut self.auth.session_ttl_secs, &args.session_ttl);
+        set(&mut self.storage.segment_size, &args.segment_size);
+        set(&mut self.shutdown.deadline_secs, &args.shutdown_deadline);
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            return invalid(format!("log_level \"{}\": {}", self.log_level, e));
+        }
+        if self.listen.http == self.listen.data {
+            return invalid(format!(
+                "the HTTP and data listeners both use {}",
+                self.listen.http
+            ));
+        }
+        if let Some(metrics) = self.listen.metrics {
+            if metrics == self.listen.http || metrics == self.listen.data {
+                return invalid(format!(
+                    "the metrics listener shares {} with another listener",
+                    metrics
+                ));
+            }
+        }
+        if self.paths.users.as_os_str().is_empty() || self.paths.data.as_os_str().is_empty() {
+            return invalid("the users and data directories must not be empty".to_owned());
+        }
+        if !(MIN_KEY_SIZE..=MAX_KEY_SIZE).contains(&self.keys.size)
+            || !self.keys.size.is_multiple_of(8)
+        {
+            return invalid(format!(
+                "key size {} is not a multiple of 8 between {} and {}",
+                self.keys.size, MIN_KEY_SIZE, MAX_KEY_SIZE
+            ));
+        }
+        for (name, secs) in [
+            ("challenge_ttl_secs", self.auth.challenge_ttl_secs),
+            ("session_ttl_secs", self.auth.session_ttl_secs),
+            ("user_reload_secs", self.auth.user_reload_secs),
+            ("deadline_secs", self.shutdown.deadline_secs),
+        ] {
+            if secs == 0 {
+                return invalid(format!("{} must be at least 1", name));
+            }
+        }
+        if !(1..=8).contains(&self.sensors.legacy_counter_width) {
+            return invalid(format!(
+                "legacy_counter_width {} is not between 1 and 8",
+                self.sensors.legacy_counter_width
+            ));
+        }
+        if self.sensors.error_budget == 0 {
+            return invalid("error_budget must be at least 1".to_owned());
+        }
+        if self.storage.segment_size == 0 {
+            return invalid("segment_size must be at least 1".to_owned());
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return invalid("a TLS certificate needs a key and the other way around".to_owned());
+        }
+        if self.tls.client_ca.is_some() && self.tls.data_certificate().is_none() {
+            return invalid("client_ca needs TLS on the data port".to_owned());
+        }
+
+        Ok(())
+    }
+
+    pub fn readings_dir(&self) -> PathBuf {
+        self.paths.data.join("readings")
+    }
+
+    pub fn registry_path(&self) -> PathBuf {
+        self.paths.data.join("sensors.json")
+    }
+
+    pub fn replay_path(&self) -> PathBuf {
+        self.paths.data.join("replay.json")
+    }
+
+    pub fn audit_path(&self) -> PathBuf {
+        self.paths.data.join("audit.log")
+    }
+
+    pub fn server_key_path(&self) -> PathBuf {
+        self.paths
+            .server_key
+            .clone()
+            .unwrap_or_else(|| self.paths.data.join("server_key.pem"))
+    }
+
+    pub fn master_key_path(&self) -> PathBuf {
+        self.paths
+            .master_key
+            .clone()
+            .unwrap_or_else(|| self.paths.data.join("master.key"))
+    }
+
+    /// The configuration as TOML, with the default key paths spelled out.
+    pub fn to_toml(&self) -> String {
+        let mut resolved = self.clone();
+        resolved.paths.server_key = Some(self.server_key_path());
+        resolved.paths.master_key = Some(self.master_key_path());
+
+        toml::to_string(&resolved).unwrap()
+    }
+}
+
+impl AuthConfig {
+    pub fn challenge_ttl(&self) -> Duration {
+        Duration::from_secs(self.challenge_ttl_secs)
+    }
+
+    pub fn session_ttl(&self) -> Duration {
+        Duration::from_secs(self.session_ttl_secs)
+    }
+
+    pub fn user_reload_interval(&self) -> Duration {
+        Duration::from_secs(self.user_reload_secs)
+    }
+}
+
+impl KeyConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+impl TlsConfig {
+    /// Certificate and key of the HTTP listener, if it uses TLS.
+    pub fn http_certificate(&self) -> Option<(&Path, &Path)> {
+        self.certificate().filter(|_| self.http)
+    }
+
+    /// Certificate and key of the data listener, if it uses TLS.
+    pub fn data_certificate(&self) -> Option<(&Path, &Path)> {
+        self.certificate().filter(|_| self.data)
+    }
+
+    fn certificate(&self) -> Option<(&Path, &Path)> {
+        Some((self.cert.as_deref()?, self.key.as_deref()?))
+    }
+}
+
+impl SensorConfig {
+    pub fn active_window(&self) -> Duration {
+        Duration::from_secs(self.active_window_secs)
+    }
+
+    pub fn error_budget(&self) -> tcp_server::ErrorBudget {
+        tcp_server::ErrorBudget {
+            burst: self.error_budget,
+            per_sec: self.error_budget_per_sec,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn deadline(&self) -> Duration {
+        Duration::from_secs(self.deadline_secs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(["project_server"].iter().chain(flags)).unwrap()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.toml");
+        assert!(matches!(
+            Config::load(&args(&["--config", missing.to_str().unwrap()])),
+            Err(ConfigError::Read { .. })
+        ));
+
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.server_key_path(), Path::new("data/server_key.pem"));
+        assert_eq!(config.registry_path(), Path::new("data/sensors.json"));
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(
+            &path,
+            "log_level = \"info\"\n\
+             [listen]\n\
+             http = \"127.0.0.1:4000\"\n\
+             [paths]\n\
+             data = \"/var/lib/project_server\"\n\
+             [auth]\n\
+             challenge_ttl_secs = 30\n",
+        )
+        .unwrap();
+
+        let config = Config::load(&args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--challenge-ttl",
+            "10",
+            "--master-key",
+            "/etc/master.key",
+            "--metrics-addr",
+            "127.0.0.1:9100",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.log_level, "info");
+        assert_eq!(config.listen.http, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.listen.data, ListenConfig::default().data);
+        assert_eq!(
+            config.listen.metrics,
+            Some("127.0.0.1:9100".parse().unwrap())
+        );
+        assert_eq!(config.auth.challenge_ttl(), Duration::from_secs(10));
+        assert_eq!(
+            config.server_key_path(),
+            Path::new("/var/lib/project_server/server_key.pem")
+        );
+        assert_eq!(config.master_key_path(), Path::new("/etc/master.key"));
+
+        // the printed configuration loads back to the same settings
+        fs::write(&path, config.to_toml()).unwrap();
+        let reloaded = Config::load(&args(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(reloaded.to_toml(), config.to_toml());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+
+        for contents in [
+            "unknown = 1",
+            "[listen]\nhttp = \"not an address\"",
+            "[auth]\nsession_ttl_secs = -1",
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert!(matches!(
+                Config::load(&args(&["--config", path.to_str().unwrap()])),
+                Err(ConfigError::Parse { .. })
+            ));
+        }
+
+        fs::write(&path, "").unwrap();
+        for flags in [
+            ["--key-size", "1024"],
+            ["--key-size", "2049"],
+            ["--challenge-ttl", "0"],
+            ["--segment-size", "0"],
+            ["--shutdown-deadline", "0"],
+            ["--data-addr", "0.0.0.0:3000"],
+            ["--metrics-addr", "0.0.0.0:8000"],
+            ["--log-level", "project_server=loud"],
+            ["--tls-cert", "cert.pem"],
+            ["--tls-client-ca", "ca.pem"],
+        ] {
+            let mut flags = flags.to_vec();
+            flags.extend(["--config", path.to_str().unwrap()]);
+            assert!(matches!(
+                Config::load(&args(&flags)),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+
+        for contents in [
+            "[sensors]\nerror_budget = 0",
+            "[sensors]\nlegacy_counter_width = 9",
+        ] {
+            fs::write(&path, contents).unwrap();
+            assert!(matches!(
+                Config::load(&args(&["--config", path.to_str().unwrap()])),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,590 @@
+//! Framing of the sensor data protocol.
+//!
+//! Version 1 frames are laid out as follows, multi-byte integers are little
+//! endian:
+//!
+//! | size | field                                         |
+//! |------|-----------------------------------------------|
+//! | 1    | magic/version, `0xA0 \| version`              |
+//! | 1    | flags                                         |
+//! | 1    | sensor name length                            |
+//! | n    | sensor name, UTF-8                            |
+//! | 1    | counter width in bytes (1 to 8)               |
+//! | w    | packet counter                                |
+//! | 2    | payload length                                |
+//! | p    | payload (AES-CCM ciphertext)                  |
+//! | 2    | CRC-16/CCITT-FALSE of all preceding bytes     |
+//!
+//! The legacy layout, `>` name `<`, a little endian counter, a one byte
+//! length and the ciphertext, is still accepted when the codec runs in
+//! compatibility mode. The counter is as wide as the firmware's `u32` unless
+//! the codec is told otherwise.
+//!
+//! A frame with the [`FLAG_ACK_REQUESTED`] flag set is answered with an ack
+//! frame telling the sender what became of it:
+//!
+//! | size | field                                         |
+//! |------|-----------------------------------------------|
+//! | 1    | magic/version, `0xA0 \| version`              |
+//! | 1    | status, see [`AckStatus`]                     |
+//! | 1    | counter width in bytes (1 to 8)               |
+//! | w    | packet counter of the acknowledged frame      |
+//! | 2    | CRC-16/CCITT-FALSE of all preceding bytes     |
+
+use std::{fmt, io};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+pub const FRAME_VERSION: u8 = 1;
+const MAGIC_MASK: u8 = 0xF0;
+const MAGIC: u8 = 0xA0;
+const LEGACY_START: u8 = b'>';
+const LEGACY_NAME_END: u8 = b'<';
+/// Width of the legacy counter, the firmware writes a `u32`.
+pub const DEFAULT_LEGACY_COUNTER_WIDTH: u8 = 4;
+
+/// The sender waits for an ack frame.
+pub const FLAG_ACK_REQUESTED: u8 = 0x01;
+
+pub const MAX_NAME_LEN: usize = 64;
+/// Counters are limited to 40 bits because they form part of the CCM nonce.
+pub const MAX_COUNTER: u64 = (1 << 40) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameFormat {
+    Legacy,
+    V1,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub format: FrameFormat,
+    pub flags: u8,
+    pub name: String,
+    pub counter: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn ack_requested(&self) -> bool {
+        self.flags & FLAG_ACK_REQUESTED != 0
+    }
+}
+
+#[cfg(test)]
+impl Frame {
+    pub fn new(name: String, counter: u64, payload: Vec<u8>) -> Self {
+        Frame {
+            format: FrameFormat::V1,
+            flags: 0,
+            name,
+            counter,
+            payload,
+        }
+    }
+
+    /// Encodes the frame using the version 1 layout.
+    pub fn encode(&self) -> Vec<u8> {
+        let counter_width = counter_width(self.counter);
+
+        let mut bytes = Vec::with_capacity(self.name.len() + self.payload.len() + 16);
+        bytes.push(MAGIC | FRAME_VERSION);
+        bytes.push(self.flags);
+        bytes.push(self.name.len() as u8);
+        bytes.extend(self.name.as_bytes());
+        bytes.push(counter_width as u8);
+        bytes.extend(&self.counter.to_le_bytes()[..counter_width]);
+        bytes.extend((self.payload.len() as u16).to_le_bytes());
+        bytes.extend(&self.payload);
+        bytes.extend(crc16(&bytes).to_le_bytes());
+
+        bytes
+    }
+}
+
+/// What became of a frame, as reported in its ack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum AckStatus {
+    /// The reading was stored.
+    Accepted = 0,
+    DecryptFailure = 1,
+    UnknownSensor = 2,
+    /// The counter was already accepted or fell out of the replay window.
+    Replayed = 3,
+    /// The payload doesn't match the sensor's fields.
+    SchemaError = 4,
+    /// The reading was valid but couldn't be stored, sending it again may
+    /// succeed.
+    StorageFailure = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ack {
+    pub counter: u64,
+    pub status: AckStatus,
+}
+
+impl Ack {
+    pub fn encode(&self) -> Vec<u8> {
+        let counter_width = counter_width(self.counter);
+
+        let mut bytes = Vec::with_capacity(counter_width + 5);
+        bytes.push(MAGIC | FRAME_VERSION);
+        bytes.push(self.status as u8);
+        bytes.push(counter_width as u8);
+        bytes.extend(&self.counter.to_le_bytes()[..counter_width]);
+        bytes.extend(crc16(&bytes).to_le_bytes());
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+impl Ack {
+    /// Decodes an ack, `None` if `bytes` isn't exactly one valid ack.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (&[magic, status, width], rest) = bytes.split_first_chunk::<3>()?;
+        let width = width as usize;
+        if magic != MAGIC | FRAME_VERSION || !(1..=8).contains(&width) {
+            return None;
+        }
+        if rest.len() != width + 2 || crc16(&bytes[..3 + width]).to_le_bytes() != rest[width..] {
+            return None;
+        }
+
+        let status = match status {
+            0 => AckStatus::Accepted,
+            1 => AckStatus::DecryptFailure,
+            2 => AckStatus::UnknownSensor,
+            3 => AckStatus::Replayed,
+            4 => AckStatus::SchemaError,
+            5 => AckStatus::StorageFailure,
+            _ => return None,
+        };
+        let mut counter = [0u8; 8];
+        counter[..width].copy_from_slice(&rest[..width]);
+
+        Some(Ack {
+            counter: u64::from_le_bytes(counter),
+            status,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The peer closed the connection between frames.
+    Closed,
+    Io(io::Error),
+    UnsupportedVersion(u8),
+    InvalidName,
+    InvalidCounterWidth(u8),
+    CounterOverflow(u64),
+    ChecksumMismatch {
+        expected: u16,
+        actual: u16,
+    },
+}
+
+impl FrameError {
+    /// Whether the stream can no longer be read after this error.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, FrameError::Closed | FrameError::Io(_))
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "connection closed"),
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported frame version {}", version)
+            }
+            FrameError::InvalidName => write!(f, "invalid sensor name"),
+            FrameError::InvalidCounterWidth(width) => {
+                write!(f, "invalid counter width {}", width)
+            }
+            FrameError::CounterOverflow(counter) => {
+                write!(f, "counter {} does not fit in the nonce", counter)
+            }
+            FrameError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch, expected {:#06x} got {:#06x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Reads frames from a data connection.
+///
+/// Bytes that do not start a frame are skipped and counted, so a reader can
+/// resynchronize after garbage or a corrupted frame.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    accept_legacy: bool,
+    legacy_counter_width: u8,
+    skipped: usize,
+}
+
+impl FrameCodec {
+    pub fn new(accept_legacy: bool) -> Self {
+        FrameCodec {
+            accept_legacy,
+            legacy_counter_width: DEFAULT_LEGACY_COUNTER_WIDTH,
+            skipped: 0,
+        }
+    }
+
+    /// Reads legacy counters `width` bytes wide, 1 to 8, for senders that
+    /// don't write the firmware's `u32`.
+    pub fn with_legacy_counter_width(mut self, width: u8) -> Self {
+        assert!((1..=8).contains(&width), "invalid counter width {}", width);
+        self.legacy_counter_width = width;
+        self
+    }
+
+    /// Number of bytes skipped while looking for a frame since the last call.
+    pub fn take_skipped(&mut self) -> usize {
+        std::mem::take(&mut self.skipped)
+    }
+
+    pub async fn read_frame<R>(&mut self, reader: &mut R) -> Result<Frame, FrameError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let start = self.find_start(reader).await?;
+
+        if start == LEGACY_START {
+            return read_legacy(reader, self.legacy_counter_width).await;
+        }
+
+        let version = start & !MAGIC_MASK;
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        read_v1(reader, start).await
+    }
+
+    fn is_start(&self, byte: u8) -> bool {
+        byte & MAGIC_MASK == MAGIC || (self.accept_legacy && byte == LEGACY_START)
+    }
+
+    async fn find_start<R>(&mut self, reader: &mut R) -> Result<u8, FrameError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        loop {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(FrameError::Closed);
+            }
+
+            if let Some(position) = buf.iter().position(|b| self.is_start(*b)) {
+                let start = buf[position];
+                reader.consume(position + 1);
+                self.skipped += position;
+                return Ok(start);
+            }
+
+            let len = buf.len();
+            reader.consume(len);
+            self.skipped += len;
+        }
+    }
+}
+
+async fn read_legacy<R>(reader: &mut R, counter_width: u8) -> Result<Frame, FrameError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // read sensor name, a peer that never ends it must not grow the buffer
+    let mut name = Vec::new();
+    (&mut *reader)
+        .take(MAX_NAME_LEN as u64 + 1)
+        .read_until(LEGACY_NAME_END, &mut name)
+        .await?;
+    if name.last() != Some(&LEGACY_NAME_END) {
+        if name.len() > MAX_NAME_LEN {
+            return Err(FrameError::InvalidName);
+        }
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    name.pop();
+    let name = String::from_utf8(name).map_err(|_| FrameError::InvalidName)?;
+
+    let mut counter = [0u8; 8];
+    reader
+        .read_exact(&mut counter[..counter_width as usize])
+        .await?;
+
+    let payload_len = reader.read_u8().await?;
+    let mut payload = vec![0u8; payload_len as usize];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Frame {
+        format: FrameFormat::Legacy,
+        flags: 0,
+        name,
+        counter: u64::from_le_bytes(counter),
+        payload,
+    })
+}
+
+async fn read_v1<R>(reader: &mut R, magic: u8) -> Result<Frame, FrameError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut raw = vec![magic];
+
+    let flags = reader.read_u8().await?;
+    let name_len = reader.read_u8().await?;
+    raw.extend([flags, name_len]);
+
+    let mut name = vec![0u8; name_len as usize];
+    reader.read_exact(&mut name).await?;
+    raw.extend(&name);
+
+    let counter_width = reader.read_u8().await?;
+    raw.push(counter_width);
+    if counter_width == 0 || counter_width > 8 {
+        return Err(FrameError::InvalidCounterWidth(counter_width));
+    }
+    let mut counter = [0u8; 8];
+    reader
+        .read_exact(&mut counter[..counter_width as usize])
+        .await?;
+    raw.extend(&counter[..counter_width as usize]);
+
+    let mut payload_len = [0u8; 2];
+    reader.read_exact(&mut payload_len).await?;
+    raw.extend(payload_len);
+
+    let mut payload = vec![0u8; u16::from_le_bytes(payload_len) as usize];
+    reader.read_exact(&mut payload).await?;
+    raw.extend(&payload);
+
+    let mut checksum = [0u8; 2];
+    reader.read_exact(&mut checksum).await?;
+    let expected = crc16(&raw);
+    let actual = u16::from_le_bytes(checksum);
+    if expected != actual {
+        return Err(FrameError::ChecksumMismatch { expected, actual });
+    }
+
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(FrameError::InvalidName);
+    }
+    let name = String::from_utf8(name).map_err(|_| FrameError::InvalidName)?;
+
+    let counter = u64::from_le_bytes(counter);
+    if counter > MAX_COUNTER {
+        return Err(FrameError::CounterOverflow(counter));
+    }
+
+    Ok(Frame {
+        format: FrameFormat::V1,
+        flags,
+        name,
+        counter,
+        payload,
+    })
+}
+
+/// Smallest number of bytes that can hold `counter`.
+fn counter_width(counter: u64) -> usize {
+    (8 - counter.leading_zeros() as usize / 8).max(1)
+}
+
+/// CRC-16/CCITT-FALSE, small enough to be computed on the sensor as well.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn legacy_bytes(name: &str, counter: u64, payload: &[u8]) -> Vec<u8> {
+        legacy_bytes_with_width(name, counter, payload, DEFAULT_LEGACY_COUNTER_WIDTH)
+    }
+
+    fn legacy_bytes_with_width(name: &str, counter: u64, payload: &[u8], width: u8) -> Vec<u8> {
+        let mut bytes = vec![LEGACY_START];
+        bytes.extend(name.as_bytes());
+        bytes.push(LEGACY_NAME_END);
+        bytes.extend(&counter.to_le_bytes()[..width as usize]);
+        bytes.push(payload.len() as u8);
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn counter_widths() {
+        assert_eq!(counter_width(0), 1);
+        assert_eq!(counter_width(0xFF), 1);
+        assert_eq!(counter_width(0x100), 2);
+        assert_eq!(counter_width(u32::MAX as u64), 4);
+        assert_eq!(counter_width(MAX_COUNTER), 5);
+    }
+
+    #[tokio::test]
+    async fn v1_round_trip() {
+        let frame = Frame::new("example_sensor".to_owned(), 300, vec![1, 2, 3]);
+        let bytes = frame.encode();
+
+        let mut codec = FrameCodec::new(false);
+        let decoded = codec.read_frame(&mut &bytes[..]).await.unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(codec.take_skipped(), 0);
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        for counter in [0, 0x1234, MAX_COUNTER] {
+            let ack = Ack {
+                counter,
+                status: AckStatus::Replayed,
+            };
+            let bytes = ack.encode();
+            assert_eq!(bytes.len(), 5 + counter_width(counter));
+            assert_eq!(Ack::decode(&bytes), Some(ack));
+
+            let mut corrupted = bytes.clone();
+            corrupted[1] = AckStatus::Accepted as u8;
+            assert_eq!(Ack::decode(&corrupted), None);
+            assert_eq!(Ack::decode(&bytes[..bytes.len() - 1]), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_frames() {
+        // laid out like the firmware writes it, with a u32 counter
+        let mut bytes = b">example_sensor<".to_vec();
+        bytes.extend(0x0102_0304u32.to_le_bytes());
+        bytes.push(10);
+        bytes.extend(b"ciphertext");
+        bytes.extend(legacy_bytes("example_sensor", 8, b"abc"));
+
+        let mut codec = FrameCodec::new(true);
+        let mut reader = &bytes[..];
+        let frame = codec.read_frame(&mut reader).await.unwrap();
+        assert_eq!(frame.format, FrameFormat::Legacy);
+        assert_eq!(frame.name, "example_sensor");
+        assert_eq!(frame.counter, 0x0102_0304);
+        assert_eq!(frame.payload, b"ciphertext");
+        assert_eq!(codec.read_frame(&mut reader).await.unwrap().counter, 8);
+
+        // senders with wider counters need the codec told so
+        let bytes = legacy_bytes_with_width("sensor", 1 << 32, b"abc", 5);
+        let mut codec = FrameCodec::new(true).with_legacy_counter_width(5);
+        let frame = codec.read_frame(&mut &bytes[..]).await.unwrap();
+        assert_eq!(frame.counter, 1 << 32);
+        assert_eq!(frame.payload, b"abc");
+
+        // without compatibility mode the legacy frame is skipped entirely
+        let mut codec = FrameCodec::new(false);
+        let bytes = legacy_bytes("sensor", 7, b"abc");
+        let result = codec.read_frame(&mut &bytes[..]).await;
+        assert!(matches!(result, Err(FrameError::Closed)));
+        assert_eq!(codec.take_skipped(), bytes.len());
+    }
+
+    #[tokio::test]
+    async fn legacy_name_is_bounded() {
+        let longest = "n".repeat(MAX_NAME_LEN);
+        let bytes = legacy_bytes(&longest, 1, b"abc");
+        let mut codec = FrameCodec::new(true);
+        assert_eq!(
+            codec.read_frame(&mut &bytes[..]).await.unwrap().name,
+            longest
+        );
+
+        // a name that never ends is cut off at the limit
+        let mut bytes = vec![LEGACY_START];
+        bytes.extend(vec![b'n'; 10_000]);
+        bytes.extend(legacy_bytes("sensor", 2, b"abc"));
+        let mut reader = &bytes[..];
+        let result = codec.read_frame(&mut reader).await;
+        assert!(matches!(result, Err(FrameError::InvalidName)));
+        assert!(!result.unwrap_err().is_fatal());
+        assert_eq!(reader.len(), bytes.len() - MAX_NAME_LEN - 2);
+
+        // the next frame is still readable
+        assert_eq!(codec.read_frame(&mut reader).await.unwrap().counter, 2);
+    }
+
+    #[tokio::test]
+    async fn skips_garbage() {
+        let frame = Frame::new("sensor".to_owned(), 1, vec![9; 20]);
+        let mut bytes = b"garbage".to_vec();
+        bytes.extend(frame.encode());
+
+        let mut codec = FrameCodec::new(true);
+        assert_eq!(codec.read_frame(&mut &bytes[..]).await.unwrap(), frame);
+        assert_eq!(codec.take_skipped(), 7);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch() {
+        let first = Frame::new("sensor".to_owned(), 1, vec![1, 2, 3]);
+        let second = Frame::new("sensor".to_owned(), 2, vec![4, 5, 6]);
+        let mut bytes = first.encode();
+        let last = bytes.len() - 3;
+        bytes[last] ^= 0xFF;
+        bytes.extend(second.encode());
+
+        let mut codec = FrameCodec::new(false);
+        let mut reader = &bytes[..];
+        let result = codec.read_frame(&mut reader).await;
+        assert!(matches!(result, Err(FrameError::ChecksumMismatch { .. })));
+        assert!(!result.unwrap_err().is_fatal());
+
+        // the next frame is still readable
+        assert_eq!(codec.read_frame(&mut reader).await.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_version() {
+        let mut bytes = Frame::new("sensor".to_owned(), 1, vec![]).encode();
+        bytes[0] = MAGIC | 0x0F;
+
+        let mut codec = FrameCodec::new(false);
+        let result = codec.read_frame(&mut &bytes[..]).await;
+        assert!(matches!(result, Err(FrameError::UnsupportedVersion(0x0F))));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        let bytes = Frame::new("sensor".to_owned(), 1, vec![1, 2, 3]).encode();
+
+        let mut codec = FrameCodec::new(false);
+        let result = codec.read_frame(&mut &bytes[..bytes.len() - 1]).await;
+        assert!(result.unwrap_err().is_fatal());
+    }
+}
@@ -0,0 +1,316 @@
+//! The server's RSA identity key.
+//!
+//! The private key is kept in a PEM file so clients can pin the public key
+//! across restarts. After a rotation the previous key is kept in a second
+//! file and still accepted until its grace period runs out.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rsa::{
+    pkcs1::EncodeRsaPublicKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    sha2::{Digest, Sha256},
+    Oaep, RsaPrivateKey, RsaPublicKey,
+};
+use serde::Serialize;
+use tracing::{event, Level};
+
+pub const DEFAULT_KEY_SIZE: usize = 2048;
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+const KEY_ID_SIZE: usize = 8;
+
+struct IdentityKey {
+    id: String,
+    private_key: RsaPrivateKey,
+    public_key: RsaPublicKey,
+    /// When the key stopped being the current key.
+    retired_at: Option<SystemTime>,
+}
+
+impl IdentityKey {
+    fn new(private_key: RsaPrivateKey, retired_at: Option<SystemTime>) -> Self {
+        let public_key = RsaPublicKey::from(&private_key);
+        IdentityKey {
+            id: key_id(&public_key),
+            private_key,
+            public_key,
+            retired_at,
+        }
+    }
+}
+
+struct IdentityKeys {
+    current: IdentityKey,
+    previous: Option<IdentityKey>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicKeyInfo {
+    pub id: String,
+    pub public_key: String,
+    /// Unix time in seconds after which the key is no longer accepted.
+    pub expires_at: Option<u64>,
+}
+
+pub struct ServerIdentity {
+    path: PathBuf,
+    previous_path: PathBuf,
+    key_size: usize,
+    grace_period: Duration,
+    keys: RwLock<IdentityKeys>,
+}
+
+impl ServerIdentity {
+    /// Loads the identity key from `path`, generating and saving one if the
+    /// file does not exist yet.
+    pub fn load_or_generate(
+        path: impl AsRef<Path>,
+        key_size: usize,
+        grace_period: Duration,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let previous_path = path.with_extension("previous.pem");
+
+        let current = match fs::read_to_string(&path) {
+            Ok(pem) => IdentityKey::new(parse_private_key(&pem)?, None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                event!(
+                    Level::WARN,
+                    "No server key found, generating a new one at {}",
+                    path.display()
+                );
+                let private_key = generate_key(key_size)?;
+                write_private_key(&path, &private_key)?;
+                IdentityKey::new(private_key, None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let previous = match fs::read_to_string(&previous_path) {
+            Ok(pem) => {
+                // the previous key file is written when the key is retired
+                let retired_at = fs::metadata(&previous_path)?.modified()?;
+                Some(IdentityKey::new(parse_private_key(&pem)?, Some(retired_at)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        event!(Level::INFO, "Loaded server key {}", current.id);
+
+        Ok(ServerIdentity {
+            path,
+            previous_path,
+            key_size,
+            grace_period,
+            keys: RwLock::new(IdentityKeys { current, previous }),
+        })
+    }
+
+    /// Id and public key of the key clients should encrypt to.
+    pub fn current(&self) -> (String, RsaPublicKey) {
+        let keys = self.keys.read().unwrap();
+        (keys.current.id.clone(), keys.current.public_key.clone())
+    }
+
+    /// Every key that is currently accepted, newest first.
+    pub fn public_keys(&self) -> Vec<PublicKeyInfo> {
+        let keys = self.keys.read().unwrap();
+
+        let mut infos = vec![public_key_info(&keys.current, None)];
+        if let Some(previous) = self.active_previous(&keys) {
+            let expires_at = previous.retired_at.map(|t| t + self.grace_period);
+            infos.push(public_key_info(previous, expires_at));
+        }
+
+        infos
+    }
+
+    /// Decrypts an RSA-OAEP ciphertext with the key named by `key_id`.
+    ///
+    /// Without a key id the current key is tried first, then the previous
+    /// key if it is still within its grace period.
+    pub fn decrypt(&self, key_id: Option<&str>, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.read().unwrap();
+
+        for key in [Some(&keys.current), self.active_previous(&keys)]
+            .into_iter()
+            .flatten()
+        {
+            if key_id.is_some_and(|id| id != key.id) {
+                continue;
+            }
+            if let Ok(plaintext) = key.private_key.decrypt(Oaep::new::<Sha256>(), ciphertext) {
+                return Some(plaintext);
+            }
+        }
+
+        None
+    }
+
+    /// Replaces the current key with a freshly generated one. The old key
+    /// stays valid for the grace period. Returns the id of the new key.
+    pub fn rotate(&self) -> io::Result<String> {
+        let private_key = generate_key(self.key_size)?;
+
+        let mut keys = self.keys.write().unwrap();
+        write_private_key(&self.previous_path, &keys.current.private_key)?;
+        write_private_key(&self.path, &private_key)?;
+
+        let mut previous = IdentityKey::new(private_key, None);
+        std::mem::swap(&mut keys.current, &mut previous);
+        previous.retired_at = Some(SystemTime::now());
+
+        event!(
+            Level::INFO,
+            "Rotated server key {} to {}",
+            previous.id,
+            keys.current.id
+        );
+        keys.previous = Some(previous);
+
+        Ok(keys.current.id.clone())
+    }
+
+    fn active_previous<'a>(&self, keys: &'a IdentityKeys) -> Option<&'a IdentityKey> {
+        keys.previous.as_ref().filter(|previous| {
+            previous
+                .retired_at
+                .is_some_and(|t| t + self.grace_period > SystemTime::now())
+        })
+    }
+}
+
+/// Short identifier of a public key, the hex encoded start of the SHA-256
+/// digest of its PKCS#1 DER encoding.
+pub fn key_id(public_key: &RsaPublicKey) -> String {
+    let der = public_key.to_pkcs1_der().unwrap();
+    Sha256::digest(der.as_bytes())[..KEY_ID_SIZE]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn public_key_info(key: &IdentityKey, expires_at: Option<SystemTime>) -> PublicKeyInfo {
+    PublicKeyInfo {
+        id: key.id.clone(),
+        public_key: key.public_key.to_pkcs1_pem(LineEnding::LF).unwrap(),
+        expires_at: expires_at.map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+    }
+}
+
+fn generate_key(key_size: usize) -> io::Result<RsaPrivateKey> {
+    let mut rng = rand::thread_rng();
+    RsaPrivateKey::new(&mut rng, key_size).map_err(io::Error::other)
+}
+
+fn parse_private_key(pem: &str) -> io::Result<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs8_pem(pem).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_private_key(path: &Path, private_key: &RsaPrivateKey) -> io::Result<()> {
+    let pem = private_key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(io::Error::other)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    {
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(pem.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_KEY_SIZE: usize = 1024;
+
+    fn encrypt(public_key: &RsaPublicKey, message: &[u8]) -> Vec<u8> {
+        public_key
+            .encrypt(&mut rand::thread_rng(), Oaep::new::<Sha256>(), message)
+            .unwrap()
+    }
+
+    #[test]
+    fn key_is_stable_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server_key.pem");
+
+        let identity =
+            ServerIdentity::load_or_generate(&path, TEST_KEY_SIZE, DEFAULT_GRACE_PERIOD).unwrap();
+        let (id, _) = identity.current();
+        assert!(path.exists());
+
+        let identity =
+            ServerIdentity::load_or_generate(&path, TEST_KEY_SIZE, DEFAULT_GRACE_PERIOD).unwrap();
+        assert_eq!(identity.current().0, id);
+        assert_eq!(identity.public_keys().len(), 1);
+    }
+
+    #[test]
+    fn rotation_keeps_previous_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server_key.pem");
+        let identity =
+            ServerIdentity::load_or_generate(&path, TEST_KEY_SIZE, DEFAULT_GRACE_PERIOD).unwrap();
+
+        let (old_id, old_key) = identity.current();
+        let new_id = identity.rotate().unwrap();
+        assert_ne!(old_id, new_id);
+
+        let ciphertext = encrypt(&old_key, b"secret");
+        assert_eq!(
+            identity.decrypt(Some(&old_id), &ciphertext).unwrap(),
+            b"secret"
+        );
+        assert_eq!(identity.decrypt(None, &ciphertext).unwrap(), b"secret");
+        assert!(identity.decrypt(Some(&new_id), &ciphertext).is_none());
+
+        let infos = identity.public_keys();
+        assert_eq!(infos[0].id, new_id);
+        assert_eq!(infos[1].id, old_id);
+        assert!(infos[1].expires_at.is_some());
+
+        // both keys survive a restart
+        let identity =
+            ServerIdentity::load_or_generate(&path, TEST_KEY_SIZE, DEFAULT_GRACE_PERIOD).unwrap();
+        assert_eq!(identity.current().0, new_id);
+        assert_eq!(identity.decrypt(None, &ciphertext).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn previous_key_expires() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server_key.pem");
+        let identity =
+            ServerIdentity::load_or_generate(&path, TEST_KEY_SIZE, Duration::ZERO).unwrap();
+
+        let (old_id, old_key) = identity.current();
+        identity.rotate().unwrap();
+
+        let ciphertext = encrypt(&old_key, b"secret");
+        assert!(identity.decrypt(Some(&old_id), &ciphertext).is_none());
+        assert!(identity.decrypt(None, &ciphertext).is_none());
+        assert_eq!(identity.public_keys().len(), 1);
+    }
+}
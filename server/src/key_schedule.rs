@@ -0,0 +1,167 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use sha2::{Digest, Sha256};
+
+pub const KEY_SIZE: usize = 16;
+const INTERVAL_COUNTER_SIZE: usize = 4;
+const CACHE_SIZE: usize = 4;
+
+/// Derives the AES-128 key a sensor rotates to at `interval_counter`.
+///
+/// Mirrors `update_key` in the sensor firmware: the first four bytes of the
+/// seed are replaced by the big-endian interval counter, the whole seed is
+/// hashed with SHA-256 and the first 16 bytes of the digest become the key.
+/// The firmware only calls it when the interval changes, so interval 0 uses
+/// its initial key instead, see [`key_for_counter`].
+pub fn derive_key(seed: &[u8], interval_counter: u32) -> [u8; KEY_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(interval_counter.to_be_bytes());
+    hasher.update(&seed[INTERVAL_COUNTER_SIZE.min(seed.len())..]);
+
+    hasher.finalize()[..KEY_SIZE].try_into().unwrap()
+}
+
+/// Number of completed key intervals before packet `counter`, `None` if it
+/// doesn't fit the firmware's `u32` interval counter. Truncating it would let
+/// two intervals share a key.
+///
+/// An interval of 0 disables rotation.
+pub fn interval_counter(counter: u64, interval: u32) -> Option<u32> {
+    if interval == 0 {
+        return Some(0);
+    }
+
+    u32::try_from(counter / interval as u64).ok()
+}
+
+/// The AES-128 key a sensor encrypted packet `counter` with.
+///
+/// Until its first rotation the firmware encrypts with the key it was
+/// flashed with, every later interval's key is derived from the seed. `None`
+/// if the counter is past the last interval.
+pub fn key_for_counter(
+    initial_key: &[u8; KEY_SIZE],
+    seed: &[u8],
+    interval: u32,
+    counter: u64,
+    cache: &KeyCache,
+) -> Option<[u8; KEY_SIZE]> {
+    let key = match interval_counter(counter, interval)? {
+        0 => *initial_key,
+        interval_counter => cache.get_or_derive(seed, interval_counter),
+    };
+    Some(key)
+}
+
+/// Small cache of recently derived keys so a SHA-256 isn't needed per packet.
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    keys: Mutex<VecDeque<(u32, [u8; KEY_SIZE])>>,
+}
+
+impl KeyCache {
+    pub fn get_or_derive(&self, seed: &[u8], interval_counter: u32) -> [u8; KEY_SIZE] {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some((_, key)) = keys.iter().find(|(i, _)| *i == interval_counter) {
+            return *key;
+        }
+
+        let key = derive_key(seed, interval_counter);
+        if keys.len() == CACHE_SIZE {
+            keys.pop_front();
+        }
+        keys.push_back((interval_counter, key));
+
+        key
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // direct port of the firmware's update_key
+    fn firmware_key(seed: &mut [u8; 260], interval_counter: u32) -> [u8; KEY_SIZE] {
+        let bytes = interval_counter.to_be_bytes();
+        seed[..4].copy_from_slice(&bytes);
+
+        let mut hasher = Sha256::new();
+        hasher.update(&seed[..]);
+        hasher.finalize()[0..16].try_into().unwrap()
+    }
+
+    #[test]
+    fn matches_firmware() {
+        let mut seed = [0u8; 260];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        for interval in [0, 1, 2, 1000, u32::MAX] {
+            assert_eq!(
+                derive_key(&seed, interval),
+                firmware_key(&mut seed.clone(), interval)
+            );
+        }
+    }
+
+    #[test]
+    fn interval_boundaries() {
+        assert_eq!(interval_counter(0, 10), Some(0));
+        assert_eq!(interval_counter(9, 10), Some(0));
+        assert_eq!(interval_counter(10, 10), Some(1));
+        assert_eq!(interval_counter(25, 10), Some(2));
+        assert_eq!(interval_counter(25, 0), Some(0));
+
+        // counters past the last u32 interval would wrap around
+        let last = u32::MAX as u64 * 10;
+        assert_eq!(interval_counter(last + 9, 10), Some(u32::MAX));
+        assert_eq!(interval_counter(last + 10, 10), None);
+    }
+
+    #[test]
+    fn starts_with_the_initial_key() {
+        let initial_key = [9u8; KEY_SIZE];
+        let seed = [7u8; 260];
+        let cache = KeyCache::default();
+
+        for counter in 0..10 {
+            assert_eq!(
+                key_for_counter(&initial_key, &seed, 10, counter, &cache),
+                Some(initial_key)
+            );
+        }
+        assert_eq!(
+            key_for_counter(&initial_key, &seed, 10, 10, &cache),
+            Some(derive_key(&seed, 1))
+        );
+        assert_eq!(
+            key_for_counter(&initial_key, &seed, 10, 25, &cache),
+            Some(derive_key(&seed, 2))
+        );
+        // without rotation the initial key is used forever
+        assert_eq!(
+            key_for_counter(&initial_key, &seed, 0, 1000, &cache),
+            Some(initial_key)
+        );
+        assert_eq!(
+            key_for_counter(&initial_key, &seed, 1, 1 << 32, &cache),
+            None
+        );
+    }
+
+    #[test]
+    fn cache_returns_derived_keys() {
+        let seed = [7u8; 260];
+        let cache = KeyCache::default();
+
+        for interval in 0..(CACHE_SIZE as u32 * 2) {
+            assert_eq!(
+                cache.get_or_derive(&seed, interval),
+                derive_key(&seed, interval)
+            );
+        }
+        assert_eq!(cache.keys.lock().unwrap().len(), CACHE_SIZE);
+        assert_eq!(cache.get_or_derive(&seed, 1), derive_key(&seed, 1));
+    }
+}
@@ -1,79 +1,242 @@
+mod audit;
+mod config;
+mod frame;
 mod http_server;
+mod identity;
+mod key_schedule;
+mod metrics;
+mod registry;
+mod replay;
+mod schema;
+mod session;
+mod signing;
+mod storage;
 mod tcp_server;
+mod tls;
+mod users;
 
-use ccm::aead::{generic_array::GenericArray, Buffer};
-use rsa::{pkcs1v15::VerifyingKey, pkcs8::DecodePublicKey, sha2::Sha256, RsaPublicKey};
+use audit::AuditLog;
+use ccm::aead::generic_array::GenericArray;
+use clap::Parser;
+use config::{Args, Config};
+use frame::FrameCodec;
+use identity::ServerIdentity;
+use key_schedule::{KeyCache, KEY_SIZE};
+use metrics::Metrics;
+use registry::RegistryStore;
+use replay::ReplayGuard;
+use schema::SchemaError;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use storage::{FieldValue, ReadingStore, SegmentLog, StoredReading};
+use users::UserStore;
 
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, RwLock},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
 
-const USER_PATH: &str = "authorized_users/";
 const SEED_SIZE: usize = 2048 / 8;
 
+/// How long open connections get to finish after a shutdown signal.
+pub const DEFAULT_SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+
     // set up tracing
     tracing_subscriber::fmt()
-        .with_env_filter("debug,project_server=debug")
+        .with_env_filter(config.log_level.as_str())
         .init();
 
-    let http_listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-
-    let data_listener = TcpListener::bind("0.0.0.0:8000").await.unwrap();
-
-    // let example_sensor = Sensor {
-    //     name: "example_sensor".to_string(),
-    //     fields: vec![
-    //         "x_accel".to_string(),
-    //         "y_accel".to_string(),
-    //         "z_accel".to_string(),
-    //     ],
-    //     field_types: vec![FieldType::Integer, FieldType::Integer, FieldType::Integer],
-    //     key: [
-    //         0xfd, 0xa4, 0x92, 0xea, 0x96, 0xad, 0xb6, 0x44, 0x8b, 0xc3, 0x74, 0xd7, 0x1a, 0x53,
-    //         0x52, 0x52,
-    //     ],
-    //     ccm_data: CcmData::new([0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8]),
-    //     interval: 10,
-    // };
-
-    let sensor_map = HashMap::new();
-    // hashmap.insert("example_sensor".to_string(), example_sensor);
-    let sensors = Arc::new(RwLock::new(sensor_map));
+    let shutdown = CancellationToken::new();
+    tokio::spawn(cancel_on_signal(shutdown.clone()));
 
-    let authorized_users = load_authorized_users();
+    let (live, _) = broadcast::channel(http_server::LIVE_BUFFER_SIZE);
+    run(config, live, shutdown).await;
+}
 
-    tokio::spawn(crate::tcp_server::serve(data_listener, sensors.clone()));
-    crate::http_server::start(http_listener, authorized_users, sensors).await;
+/// Cancels `shutdown` on SIGINT or SIGTERM.
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => event!(Level::INFO, "Received SIGINT, shutting down"),
+        _ = terminate.recv() => event!(Level::INFO, "Received SIGTERM, shutting down"),
+    }
+    shutdown.cancel();
 }
 
-fn load_authorized_users() -> HashMap<String, VerifyingKey<Sha256>> {
-    let mut users = HashMap::new();
+/// Serves both listeners until `shutdown` is cancelled, then gives open
+/// connections until the configured deadline to finish and flushes the
+/// readings and the sensor registry to disk.
+async fn run(
+    config: Config,
+    live: broadcast::Sender<Arc<StoredReading>>,
+    shutdown: CancellationToken,
+) {
+    let http_listener = TcpListener::bind(config.listen.http).await.unwrap();
 
-    for dir_entry in fs::read_dir(USER_PATH).unwrap() {
-        if dir_entry.is_err() {
-            eprint!(
-                "Encountered error reading users: {}",
-                dir_entry.unwrap_err()
-            );
-            continue;
-        }
-        let dir_entry = dir_entry.unwrap();
+    let data_listener = TcpListener::bind(config.listen.data).await.unwrap();
 
-        if !dir_entry.file_type().unwrap().is_file() {
-            continue;
-        }
+    let metrics_listener = match config.listen.metrics {
+        Some(address) => Some(TcpListener::bind(address).await.unwrap()),
+        None => None,
+    };
+
+    let registry = RegistryStore::open(config.registry_path(), config.master_key_path())
+        .expect("Couldn't open sensor registry");
+    let sensor_map = registry.load().expect("Couldn't load sensor registry");
+    let sensors = Arc::new(RwLock::new(sensor_map));
+
+    let identity = ServerIdentity::load_or_generate(
+        config.server_key_path(),
+        config.keys.size,
+        config.keys.grace_period(),
+    )
+    .expect("Couldn't load server key");
+
+    let replay =
+        Arc::new(ReplayGuard::open(config.replay_path()).expect("Couldn't load replay state"));
+    tokio::spawn(replay::flush_periodically(
+        replay.clone(),
+        replay::FLUSH_INTERVAL,
+        shutdown.clone(),
+    ));
+
+    let users = UserStore::new(&config.paths.users);
+    let authorized_users = Arc::new(RwLock::new(
+        users.load().expect("Couldn't load authorized users"),
+    ));
+    tokio::spawn(users::watch(
+        users.clone(),
+        authorized_users.clone(),
+        config.auth.user_reload_interval(),
+    ));
+
+    let store: Arc<dyn ReadingStore> = Arc::new(
+        SegmentLog::open(config.readings_dir(), config.storage.segment_size)
+            .expect("Couldn't open reading store"),
+    );
+
+    let http_tls = config.tls.http_certificate().map(|(cert, key)| {
+        tls::acceptor(cert, key, None).expect("Couldn't load the HTTP TLS certificate")
+    });
+    let data_tls = config.tls.data_certificate().map(|(cert, key)| {
+        tls::acceptor(cert, key, config.tls.client_ca.as_deref())
+            .expect("Couldn't load the data port TLS certificate")
+    });
+
+    let metrics = Arc::new(Metrics::new());
+    if let Some(listener) = metrics_listener {
+        // holds no state that needs draining, so it isn't waited for
+        let metrics = metrics.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, metrics, shutdown).await {
+                event!(Level::ERROR, "Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut data_server = tokio::spawn(tcp_server::serve(
+        data_listener,
+        data_tls,
+        FrameCodec::new(config.sensors.accept_legacy_frames)
+            .with_legacy_counter_width(config.sensors.legacy_counter_width),
+        tcp_server::Services {
+            sensors: sensors.clone(),
+            store: store.clone(),
+            replay: replay.clone(),
+            live: live.clone(),
+            error_budget: config.sensors.error_budget(),
+            metrics: metrics.clone(),
+        },
+        shutdown.clone(),
+    ));
+    let mut http_server = tokio::spawn(http_server::start(
+        http_listener,
+        http_tls,
+        authorized_users,
+        http_server::Services {
+            sensors: sensors.clone(),
+            store: store.clone(),
+            registry: registry.clone(),
+            identity,
+            replay: replay.clone(),
+            live,
+            challenge_ttl: config.auth.challenge_ttl(),
+            session_ttl: config.auth.session_ttl(),
+            active_window: config.sensors.active_window(),
+            users,
+            audit_log: AuditLog::open(config.audit_path()).expect("Couldn't open audit log"),
+            metrics,
+        },
+        shutdown.clone(),
+    ));
 
-        let user_filename = dir_entry.file_name().into_string().unwrap();
-        let username = user_filename.split('.').next().unwrap();
-        let key_string = fs::read_to_string(dir_entry.path()).unwrap();
-        let pub_key = RsaPublicKey::from_public_key_pem(&key_string).unwrap();
-        let key: VerifyingKey<Sha256> = pub_key.into();
-        users.insert(username.to_owned(), key);
+    // the servers only stop on their own if they failed
+    tokio::select! {
+        _ = shutdown.cancelled() => {}
+        _ = &mut data_server => event!(Level::ERROR, "Data server stopped, shutting down"),
+        _ = &mut http_server => event!(Level::ERROR, "HTTP server stopped, shutting down"),
     }
+    shutdown.cancel();
 
-    users
+    let deadline = config.shutdown.deadline();
+    event!(
+        Level::INFO,
+        "Waiting up to {:?} for open connections to finish",
+        deadline
+    );
+    let drained = timeout(deadline, async {
+        // a server that stopped before is already finished
+        let _ = (&mut data_server).await;
+        let _ = (&mut http_server).await;
+    })
+    .await;
+    if drained.is_err() {
+        event!(
+            Level::WARN,
+            "Connections still open after {:?}, closing them",
+            deadline
+        );
+        data_server.abort();
+        http_server.abort();
+    }
+
+    if let Err(e) = storage::blocking(&store, |store| store.flush()).await {
+        event!(Level::ERROR, "Failed to flush reading store: {}", e);
+    }
+    replay::close(&replay).await;
+    // the HTTP server has stopped, so no handler is writing the registry now
+    if let Err(e) = registry.save(&*sensors.read().await) {
+        event!(Level::ERROR, "Failed to save sensor registry: {}", e);
+    }
+    event!(Level::INFO, "Shutdown complete");
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -81,9 +244,54 @@ pub struct Sensor {
     pub name: String,
     fields: Vec<String>,
     field_types: Vec<FieldType>,
+    /// Seed the firmware derives rotated keys from.
     key: Vec<u8>,
+    /// Key the firmware encrypts with until its first rotation.
+    initial_key: [u8; KEY_SIZE],
     interval: u32,
     ccm_data: CcmData,
+    /// User that registered the sensor, set by the server.
+    #[serde(default)]
+    owner: Option<String>,
+    /// Unix time in milliseconds the sensor was registered, set by the server.
+    #[serde(default)]
+    registered_at: Option<u64>,
+    #[serde(skip)]
+    key_cache: KeyCache,
+    #[serde(skip)]
+    stats: SensorStats,
+}
+
+/// Runtime counters of a sensor. These are not persisted.
+#[derive(Debug, Default)]
+pub struct SensorStats {
+    /// Frames addressed to the sensor, whether they decrypted or not.
+    pub received_frames: AtomicU64,
+    pub decrypt_failures: AtomicU64,
+    pub replayed_frames: AtomicU64,
+    pub schema_errors: AtomicU64,
+    /// Unix time in milliseconds of the last authenticated frame, 0 if none
+    /// arrived since the server started.
+    pub last_seen: AtomicU64,
+}
+
+/// Changes to a registered sensor. Fields that are left out keep their value.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SensorUpdate {
+    fields: Option<Vec<String>>,
+    field_types: Option<Vec<FieldType>>,
+    key: Option<Vec<u8>>,
+    initial_key: Option<[u8; KEY_SIZE]>,
+    interval: Option<u32>,
+    ccm_data: Option<CcmData>,
+}
+
+impl SensorUpdate {
+    /// Whether frames sealed before the update no longer decrypt after it.
+    pub fn changes_key_material(&self) -> bool {
+        self.key.is_some() || self.initial_key.is_some() || self.ccm_data.is_some()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -100,8 +308,8 @@ impl CcmData {
         }
     }
 
-    pub fn get_nonce(&self, counter: [u8; 5]) -> GenericArray<u8, ccm::consts::U13> {
-        let mut le_bits: Vec<u8> = counter.into();
+    pub fn get_nonce(&self, counter: u64) -> GenericArray<u8, ccm::consts::U13> {
+        let mut le_bits: Vec<u8> = counter.to_le_bytes()[..5].into();
         le_bits.extend(self.iv);
 
         let nonce: [u8; 13] = le_bits.try_into().unwrap();
@@ -111,7 +319,13 @@ impl CcmData {
 }
 
 impl Sensor {
-    pub fn new(name: String, key: Vec<u8>, iv: [u8; 8], interval: u32) -> Self {
+    pub fn new(
+        name: String,
+        key: Vec<u8>,
+        initial_key: [u8; KEY_SIZE],
+        iv: [u8; 8],
+        interval: u32,
+    ) -> Self {
         assert_eq!(SEED_SIZE + 4, key.len());
 
         Sensor {
@@ -119,19 +333,211 @@ impl Sensor {
             fields: Vec::new(),
             field_types: Vec::new(),
             key,
+            initial_key,
             ccm_data: CcmData::new(iv),
             interval,
+            owner: None,
+            registered_at: None,
+            key_cache: KeyCache::default(),
+            stats: SensorStats::default(),
         }
     }
 
+    /// Returns the AES-128 key the sensor used to encrypt packet `counter`,
+    /// `None` if the counter is past its last key interval.
+    pub fn key_for_counter(&self, counter: u64) -> Option<[u8; KEY_SIZE]> {
+        key_schedule::key_for_counter(
+            &self.initial_key,
+            &self.key,
+            self.interval,
+            counter,
+            &self.key_cache,
+        )
+    }
+
+    pub fn is_owned_by(&self, user: &str) -> bool {
+        self.owner.as_deref() == Some(user)
+    }
+
+    /// Whether the sensor sent an authenticated frame within `window`.
+    pub fn seen_within(&self, window: Duration) -> bool {
+        let last_seen = self.stats.last_seen.load(Ordering::Relaxed);
+        last_seen != 0
+            && storage::now_millis().saturating_sub(last_seen) < window.as_millis() as u64
+    }
+
+    /// Applies `update` in place, returning the update that reverts it.
+    pub fn apply(&mut self, update: SensorUpdate) -> SensorUpdate {
+        if update.key.is_some() {
+            // cached keys were derived from the old seed
+            self.key_cache = KeyCache::default();
+        }
+
+        SensorUpdate {
+            fields: update.fields.map(|new| mem::replace(&mut self.fields, new)),
+            field_types: update
+                .field_types
+                .map(|new| mem::replace(&mut self.field_types, new)),
+            key: update.key.map(|new| mem::replace(&mut self.key, new)),
+            initial_key: update
+                .initial_key
+                .map(|new| mem::replace(&mut self.initial_key, new)),
+            interval: update
+                .interval
+                .map(|new| mem::replace(&mut self.interval, new)),
+            ccm_data: update
+                .ccm_data
+                .map(|new| mem::replace(&mut self.ccm_data, new)),
+        }
+    }
+
+    pub fn has_valid_key(&self) -> bool {
+        self.key.len() == SEED_SIZE + 4
+    }
+
+    /// Every field has exactly one type and no field is declared twice.
+    pub fn has_valid_schema(&self) -> bool {
+        self.fields.len() == self.field_types.len()
+            && self
+                .fields
+                .iter()
+                .enumerate()
+                .all(|(i, field)| !self.fields[..i].contains(field))
+    }
+
+    /// Parses a decrypted payload into the sensor's declared fields.
+    pub fn parse_reading(
+        &self,
+        plaintext: &[u8],
+    ) -> Result<BTreeMap<String, FieldValue>, SchemaError> {
+        schema::validate(&self.fields, &self.field_types, plaintext)
+    }
+
     pub fn add_field(&mut self, name: String, field_type: FieldType) {
         self.fields.push(name);
         self.field_types.push(field_type);
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum FieldType {
     Float,
     Integer,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::Frame;
+    use crate::key_schedule::derive_key;
+    use crate::storage::{Order, ReadingQuery};
+    use crate::tcp_server::Aes128Ccm;
+    use ccm::{aead::Aead, KeyInit};
+    use std::{collections::HashMap, fs};
+    use tokio::{io::AsyncWriteExt, net::TcpStream, time::sleep};
+
+    const SEED: [u8; SEED_SIZE + 4] = [3; SEED_SIZE + 4];
+    const INITIAL_KEY: [u8; KEY_SIZE] = [5; KEY_SIZE];
+    const INTERVAL: u32 = 10;
+
+    // the key the firmware encrypts `counter` with
+    fn firmware_key(counter: u64) -> [u8; KEY_SIZE] {
+        match counter / INTERVAL as u64 {
+            0 => INITIAL_KEY,
+            interval_counter => derive_key(&SEED, interval_counter as u32),
+        }
+    }
+
+    fn encrypted_frame(counter: u64) -> Vec<u8> {
+        let key = firmware_key(counter);
+        let nonce = CcmData::new([0; 8]).get_nonce(counter);
+        let payload = Aes128Ccm::new(&key.into())
+            .encrypt(&nonce, format!("{{\"x\": {}}}", counter).as_bytes())
+            .unwrap();
+
+        Frame::new("testSensor".to_owned(), counter, payload).encode()
+    }
+
+    #[tokio::test]
+    async fn shutdown_keeps_acknowledged_readings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.listen.http = "127.0.0.1:8109".parse().unwrap();
+        config.listen.data = "127.0.0.1:8110".parse().unwrap();
+        config.paths.users = dir.path().join("users");
+        config.paths.data = dir.path().join("data");
+        config.shutdown.deadline_secs = 5;
+        fs::create_dir_all(&config.paths.users).unwrap();
+
+        let mut sensor = Sensor::new(
+            "testSensor".to_owned(),
+            SEED.to_vec(),
+            INITIAL_KEY,
+            [0; 8],
+            INTERVAL,
+        );
+        sensor.add_field("x".to_owned(), FieldType::Integer);
+        RegistryStore::open(config.registry_path(), config.master_key_path())
+            .unwrap()
+            .save(&HashMap::from([(sensor.name.clone(), sensor)]))
+            .unwrap();
+
+        // a reading counts as acknowledged once it was stored and published
+        let (live, mut acknowledged) = broadcast::channel(1024);
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(run(config.clone(), live, shutdown.clone()));
+
+        let mut stream = loop {
+            match TcpStream::connect(config.listen.data).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(50)).await,
+            }
+        };
+        // keep sending until the server closes the connection
+        let sensor = tokio::spawn(async move {
+            let mut sent = 0;
+            while sent < 10_000 && stream.write_all(&encrypted_frame(sent)).await.is_ok() {
+                sent += 1;
+                sleep(Duration::from_millis(2)).await;
+            }
+            sent
+        });
+
+        let mut counters = Vec::new();
+        while counters.len() < 20 {
+            let reading = timeout(Duration::from_secs(30), acknowledged.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            counters.push(reading.reading.counter);
+        }
+
+        shutdown.cancel();
+        timeout(config.shutdown.deadline(), server)
+            .await
+            .expect("server didn't stop within the deadline")
+            .unwrap();
+        // readings stored while draining
+        while let Ok(reading) = acknowledged.try_recv() {
+            counters.push(reading.reading.counter);
+        }
+        assert!(sensor.await.unwrap() < 10_000);
+
+        let store = SegmentLog::open(config.readings_dir(), config.storage.segment_size).unwrap();
+        let stored: Vec<u64> = store
+            .query(&ReadingQuery {
+                sensor: "testSensor".to_owned(),
+                from: None,
+                to: None,
+                after: None,
+                limit: usize::MAX,
+                order: Order::Ascending,
+            })
+            .unwrap()
+            .iter()
+            .map(|reading| reading.reading.counter)
+            .collect();
+        assert_eq!(stored, counters);
+        assert_eq!(stored, (0..stored.len() as u64).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,367 @@
+//! Prometheus metrics of data ingestion and the HTTP API.
+//!
+//! Everything is registered with a `project_server_` prefix and served in the
+//! OpenMetrics text format on `/metrics`, which Prometheus scrapes natively.
+//! The endpoint has a listener of its own since it is unauthenticated.
+
+use std::{fmt::Write, io, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prometheus_client::{
+    encoding::{text, EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::frame::FrameFormat;
+
+/// Content type of [`Metrics::encode`].
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SensorLabels {
+    sensor: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FormatLabels {
+    format: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AuthFailureLabels {
+    reason: AuthFailure,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    /// Route the request matched, not the full path, so sensor and user
+    /// names don't each become a series.
+    route: String,
+    status: u16,
+}
+
+/// Why a request failed to authenticate.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// Missing or malformed authentication headers.
+    MalformedRequest,
+    /// Unknown or disabled user, or an invalid session token.
+    Unauthorized,
+    /// The request timestamp is outside the allowed clock skew.
+    StaleTimestamp,
+    /// No challenge was issued to the user, or it was already used.
+    MissingChallenge,
+    /// The challenge was answered after it expired.
+    ExpiredChallenge,
+    /// The signature doesn't match the request and challenge.
+    BadSignature,
+    /// The body key or the body itself couldn't be decrypted.
+    UndecryptableBody,
+    /// The decrypted body is not a valid sensor.
+    InvalidSensor,
+}
+
+impl EncodeLabelValue for AuthFailure {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        let reason = match self {
+            AuthFailure::MalformedRequest => "malformed_request",
+            AuthFailure::Unauthorized => "unauthorized",
+            AuthFailure::StaleTimestamp => "stale_timestamp",
+            AuthFailure::MissingChallenge => "missing_challenge",
+            AuthFailure::ExpiredChallenge => "expired_challenge",
+            AuthFailure::BadSignature => "bad_signature",
+            AuthFailure::UndecryptableBody => "undecryptable_body",
+            AuthFailure::InvalidSensor => "invalid_sensor",
+        };
+        encoder.write_str(reason)
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    connections_accepted: Counter,
+    frames_parsed: Family<FormatLabels, Counter>,
+    invalid_frames: Counter,
+    skipped_bytes: Counter,
+    decrypt_successes: Family<SensorLabels, Counter>,
+    decrypt_failures: Family<SensorLabels, Counter>,
+    unknown_sensor_frames: Counter,
+    challenges_issued: Counter,
+    auth_failures: Family<AuthFailureLabels, Counter>,
+    request_duration: Family<RequestLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Metrics {
+            registry: Registry::with_prefix("project_server"),
+            connections_accepted: Counter::default(),
+            frames_parsed: Family::default(),
+            invalid_frames: Counter::default(),
+            skipped_bytes: Counter::default(),
+            decrypt_successes: Family::default(),
+            decrypt_failures: Family::default(),
+            unknown_sensor_frames: Counter::default(),
+            challenges_issued: Counter::default(),
+            auth_failures: Family::default(),
+            // 1ms to about 16s, RSA decryption alone takes milliseconds
+            request_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.001, 2.0, 15))
+            }),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "data_connections_accepted",
+            "Connections accepted on the data port",
+            metrics.connections_accepted.clone(),
+        );
+        registry.register(
+            "data_frames_parsed",
+            "Frames read from data connections",
+            metrics.frames_parsed.clone(),
+        );
+        registry.register(
+            "data_invalid_frames",
+            "Frames dropped for a bad checksum, version or header",
+            metrics.invalid_frames.clone(),
+        );
+        registry.register(
+            "data_skipped_bytes",
+            "Bytes read without finding a frame start",
+            metrics.skipped_bytes.clone(),
+        );
+        registry.register(
+            "data_decrypt_successes",
+            "Frames that decrypted with their sensor's key",
+            metrics.decrypt_successes.clone(),
+        );
+        registry.register(
+            "data_decrypt_failures",
+            "Frames that failed to decrypt with their sensor's key",
+            metrics.decrypt_failures.clone(),
+        );
+        registry.register(
+            "data_unknown_sensor_frames",
+            "Frames skipped because their sensor isn't registered",
+            metrics.unknown_sensor_frames.clone(),
+        );
+        registry.register(
+            "auth_challenges_issued",
+            "Login challenges handed out",
+            metrics.challenges_issued.clone(),
+        );
+        registry.register(
+            "auth_failures",
+            "Requests that failed to authenticate",
+            metrics.auth_failures.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "Time to answer HTTP requests",
+            metrics.request_duration.clone(),
+        );
+
+        metrics
+    }
+
+    /// All metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = String::new();
+        text::encode(&mut buffer, &self.registry).unwrap();
+        buffer
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.inc();
+    }
+
+    pub fn frame_parsed(&self, format: FrameFormat) {
+        let format = match format {
+            FrameFormat::Legacy => "legacy",
+            FrameFormat::V1 => "v1",
+        };
+        self.frames_parsed
+            .get_or_create(&FormatLabels { format })
+            .inc();
+    }
+
+    pub fn invalid_frame(&self) {
+        self.invalid_frames.inc();
+    }
+
+    pub fn skipped_bytes(&self, count: usize) {
+        self.skipped_bytes.inc_by(count as u64);
+    }
+
+    pub fn decrypted(&self, sensor: &str, success: bool) {
+        let family = if success {
+            &self.decrypt_successes
+        } else {
+            &self.decrypt_failures
+        };
+        family
+            .get_or_create(&SensorLabels {
+                sensor: sensor.to_owned(),
+            })
+            .inc();
+    }
+
+    /// Drops the series of a deregistered sensor, so they neither linger in
+    /// every scrape nor carry over to a new sensor of the same name.
+    pub fn remove_sensor(&self, sensor: &str) {
+        let labels = SensorLabels {
+            sensor: sensor.to_owned(),
+        };
+        self.decrypt_successes.remove(&labels);
+        self.decrypt_failures.remove(&labels);
+    }
+
+    pub fn unknown_sensor_frame(&self) {
+        self.unknown_sensor_frames.inc();
+    }
+
+    pub fn challenge_issued(&self) {
+        self.challenges_issued.inc();
+    }
+
+    pub fn auth_failed(&self, reason: AuthFailure) {
+        self.auth_failures
+            .get_or_create(&AuthFailureLabels { reason })
+            .inc();
+    }
+}
+
+/// Middleware recording how long each routed request took.
+pub async fn record_latency(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_owned();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    let labels = RequestLabels {
+        method,
+        route,
+        status: response.status().as_u16(),
+    };
+    metrics
+        .request_duration
+        .get_or_create(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Serves `/metrics` until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let router = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn scrape(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], metrics.encode())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_labels() {
+        let metrics = Metrics::new();
+        metrics.frame_parsed(FrameFormat::Legacy);
+        metrics.skipped_bytes(7);
+        metrics.decrypted("a", true);
+        metrics.decrypted("a", false);
+        metrics.decrypted("a", false);
+        metrics.auth_failed(AuthFailure::UndecryptableBody);
+
+        let exported = metrics.encode();
+        for line in [
+            "# TYPE project_server_data_frames_parsed counter",
+            "project_server_data_frames_parsed_total{format=\"legacy\"} 1",
+            "project_server_data_skipped_bytes_total 7",
+            "project_server_data_decrypt_successes_total{sensor=\"a\"} 1",
+            "project_server_data_decrypt_failures_total{sensor=\"a\"} 2",
+            "project_server_auth_failures_total{reason=\"undecryptable_body\"} 1",
+        ] {
+            assert!(
+                exported.contains(line),
+                "{} missing from\n{}",
+                line,
+                exported
+            );
+        }
+        assert!(exported.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn removes_sensor_series() {
+        let metrics = Metrics::new();
+        metrics.decrypted("a", true);
+        metrics.decrypted("a", false);
+        metrics.decrypted("b", true);
+
+        metrics.remove_sensor("a");
+        let exported = metrics.encode();
+        assert!(!exported.contains("sensor=\"a\""), "{}", exported);
+        assert!(exported.contains("project_server_data_decrypt_successes_total{sensor=\"b\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn serves_own_listener() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.challenge_issued();
+        let shutdown = CancellationToken::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, metrics, shutdown.clone()));
+
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("application/openmetrics-text"));
+        let body = response.text().await.unwrap();
+        assert!(body.contains("project_server_auth_challenges_issued_total 1"));
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+//! On-disk persistence of the sensor registry.
+//!
+//! The registry is stored as a JSON state file. Sensor key material is sealed
+//! with AES-256-GCM under a server master key, using the sensor name as
+//! associated data so sealed keys cannot be swapped between sensors.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{event, Level};
+
+use crate::{key_schedule::KEY_SIZE, Sensor};
+
+const MASTER_KEY_SIZE: usize = 32;
+const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct RegistryFile {
+    version: u32,
+    sensors: Vec<Value>,
+}
+
+#[derive(Clone)]
+pub struct RegistryStore {
+    path: PathBuf,
+    master_key: Key<Aes256Gcm>,
+}
+
+impl RegistryStore {
+    /// Opens the registry at `path`, generating the master key on first run.
+    pub fn open(path: impl AsRef<Path>, master_key_path: impl AsRef<Path>) -> io::Result<Self> {
+        let master_key = load_or_create_master_key(master_key_path.as_ref())?;
+
+        Ok(RegistryStore {
+            path: path.as_ref().to_path_buf(),
+            master_key,
+        })
+    }
+
+    pub fn load(&self) -> io::Result<HashMap<String, Sensor>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let file: RegistryFile = serde_json::from_slice(&bytes).map_err(invalid_data)?;
+        if file.version != STATE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported registry version {}",
+                file.version
+            )));
+        }
+
+        let mut sensors = HashMap::new();
+        for sealed in file.sensors {
+            let sensor = self.unseal(sealed)?;
+            sensors.insert(sensor.name.clone(), sensor);
+        }
+
+        event!(
+            Level::INFO,
+            "Loaded {} sensors from {}",
+            sensors.len(),
+            self.path.display()
+        );
+        Ok(sensors)
+    }
+
+    /// Atomically replaces the state file with the given registry.
+    pub fn save(&self, sensors: &HashMap<String, Sensor>) -> io::Result<()> {
+        self.write(&self.encode(sensors)?)
+    }
+
+    /// Seals and serializes the registry for [`RegistryStore::write`], so the
+    /// map can be unlocked before the disk is touched.
+    pub fn encode(&self, sensors: &HashMap<String, Sensor>) -> io::Result<Vec<u8>> {
+        let file = RegistryFile {
+            version: STATE_VERSION,
+            sensors: sensors
+                .values()
+                .map(|sensor| self.seal(sensor))
+                .collect::<io::Result<_>>()?,
+        };
+        serde_json::to_vec_pretty(&file).map_err(invalid_data)
+    }
+
+    /// Atomically replaces the state file with an encoded registry. Blocks on
+    /// disk I/O.
+    pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let parent = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(parent)?;
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = fs::File::create(&tmp_path)?;
+            tmp.write_all(bytes)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        // make the rename itself durable
+        fs::File::open(parent)?.sync_all()
+    }
+
+    fn seal(&self, sensor: &Sensor) -> io::Result<Value> {
+        let cipher = Aes256Gcm::new(&self.master_key);
+        let nonce = Aes256Gcm::generate_nonce(&mut rand::thread_rng());
+        // the initial key and the seed are sealed together
+        let key_material = [&sensor.initial_key[..], &sensor.key].concat();
+        let sealed_key = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &key_material,
+                    aad: sensor.name.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid_data("failed to seal sensor key"))?;
+
+        let mut value = serde_json::to_value(sensor).map_err(invalid_data)?;
+        value["key"] = serde_json::to_value(sealed_key).map_err(invalid_data)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("initial_key");
+        }
+        value["key_nonce"] = serde_json::to_value(nonce.as_slice()).map_err(invalid_data)?;
+
+        Ok(value)
+    }
+
+    fn unseal(&self, mut value: Value) -> io::Result<Sensor> {
+        let Some(object) = value.as_object_mut() else {
+            return Err(invalid_data("sensor entry is not an object"));
+        };
+        let name = object
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid_data("sensor entry has no name"))?
+            .to_owned();
+        let sealed_key: Vec<u8> = object
+            .remove("key")
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(invalid_data)?
+            .ok_or_else(|| invalid_data("sensor entry has no key"))?;
+        let nonce: Vec<u8> = object
+            .remove("key_nonce")
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(invalid_data)?
+            .ok_or_else(|| invalid_data("sensor entry has no key nonce"))?;
+        if nonce.len() != 12 {
+            return Err(invalid_data("invalid key nonce"));
+        }
+
+        let cipher = Aes256Gcm::new(&self.master_key);
+        let mut key = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &sealed_key,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid_data(format!("failed to unseal key of sensor {}", name)))?;
+        if key.len() < KEY_SIZE {
+            return Err(invalid_data(format!("sensor {} has no initial key", name)));
+        }
+        let seed = key.split_off(KEY_SIZE);
+
+        object.insert(
+            "initial_key".to_owned(),
+            serde_json::to_value(key).map_err(invalid_data)?,
+        );
+        object.insert(
+            "key".to_owned(),
+            serde_json::to_value(seed).map_err(invalid_data)?,
+        );
+        serde_json::from_value(value).map_err(invalid_data)
+    }
+}
+
+fn load_or_create_master_key(path: &Path) -> io::Result<Key<Aes256Gcm>> {
+    match fs::read(path) {
+        Ok(bytes) if bytes.len() == MASTER_KEY_SIZE => {
+            return Ok(*Key::<Aes256Gcm>::from_slice(&bytes))
+        }
+        Ok(_) => return Err(invalid_data("master key has the wrong size")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    event!(
+        Level::WARN,
+        "No master key found, generating a new one at {}",
+        path.display()
+    );
+    let key = Aes256Gcm::generate_key(&mut rand::thread_rng());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(&key)?;
+    file.sync_all()?;
+
+    Ok(key)
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FieldType;
+
+    fn test_sensor(name: &str) -> Sensor {
+        let mut sensor = Sensor::new(name.to_owned(), vec![42u8; 260], [43; 16], [1; 8], 10);
+        sensor.add_field("x".to_owned(), FieldType::Integer);
+        sensor
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry_path = dir.path().join("sensors.json");
+        let master_key_path = dir.path().join("master.key");
+
+        let registry = RegistryStore::open(&registry_path, &master_key_path).unwrap();
+        assert!(registry.load().unwrap().is_empty());
+
+        let sensors = HashMap::from([
+            ("a".to_owned(), test_sensor("a")),
+            ("b".to_owned(), test_sensor("b")),
+        ]);
+        registry.save(&sensors).unwrap();
+
+        // key material is not stored in the clear
+        let state: Value = serde_json::from_slice(&fs::read(&registry_path).unwrap()).unwrap();
+        let stored_key: Vec<u8> =
+            serde_json::from_value(state["sensors"][0]["key"].clone()).unwrap();
+        assert_eq!(stored_key.len(), 16 + 260 + 16);
+        assert_ne!(stored_key[16..276], [42u8; 260]);
+        assert!(state["sensors"][0].get("initial_key").is_none());
+
+        let registry = RegistryStore::open(&registry_path, &master_key_path).unwrap();
+        let loaded = registry.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["a"].key, vec![42u8; 260]);
+        assert_eq!(loaded["a"].initial_key, [43; 16]);
+        assert_eq!(loaded["b"].fields, vec!["x".to_owned()]);
+        assert_eq!(loaded["b"].interval, 10);
+    }
+
+    #[test]
+    fn wrong_master_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry_path = dir.path().join("sensors.json");
+
+        let registry = RegistryStore::open(&registry_path, dir.path().join("master.key")).unwrap();
+        registry
+            .save(&HashMap::from([("a".to_owned(), test_sensor("a"))]))
+            .unwrap();
+
+        let registry = RegistryStore::open(&registry_path, dir.path().join("other.key")).unwrap();
+        assert_eq!(
+            registry.load().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn sealed_keys_are_bound_to_the_sensor() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry_path = dir.path().join("sensors.json");
+        let registry = RegistryStore::open(&registry_path, dir.path().join("master.key")).unwrap();
+        registry
+            .save(&HashMap::from([("a".to_owned(), test_sensor("a"))]))
+            .unwrap();
+
+        let state = fs::read_to_string(&registry_path).unwrap();
+        fs::write(&registry_path, state.replace("\"a\"", "\"b\"")).unwrap();
+        assert!(registry.load().is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! Replay protection for the data channel.
+//!
+//! Every sensor has a sliding window over its packet counters, in the style
+//! of IPsec/DTLS anti-replay: the highest accepted counter plus a bitmap of
+//! the counters just below it. Frames may arrive out of order within the
+//! window, anything older or already seen is rejected. The windows are
+//! checked in memory and written to a state file every [`FLUSH_INTERVAL`]
+//! and at shutdown, so a restart doesn't reopen them.
+//!
+//! A crash must not reopen them either, so every window also reserves the
+//! next [`RESERVATION`] counters in the state file. A counter past the saved
+//! reservation is only accepted once a new one is written, see [`admit`].
+//! After a crash the window starts at the reservation, which skips the
+//! counters reserved but never used instead of accepting any twice.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{event, Level};
+
+/// Number of counters below the highest one that are still accepted.
+pub const WINDOW_SIZE: u64 = 64;
+
+/// How often changed windows are written to the state file.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counters a window reserves past the highest accepted one. The state file
+/// is written before acknowledging at least every this many frames of a
+/// sensor, and a crash skips at most this many of its counters.
+pub const RESERVATION: u64 = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    /// The counter was already accepted.
+    Duplicate,
+    /// The counter is older than the window.
+    Stale { highest: u64 },
+    /// The counter is past the saved reservation and the state file couldn't
+    /// be written.
+    Unsaved,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Duplicate => write!(f, "duplicate counter"),
+            ReplayError::Stale { highest } => {
+                write!(f, "counter is too far behind {}", highest)
+            }
+            ReplayError::Unsaved => write!(f, "replay state couldn't be saved"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set if `highest - i` was accepted.
+    bitmap: u64,
+    /// Highest counter that may be accepted before the state file has to be
+    /// written again.
+    #[serde(default)]
+    reserved: u64,
+    /// Reservation known to be in the state file.
+    #[serde(skip)]
+    saved: Option<u64>,
+}
+
+impl ReplayWindow {
+    fn new(counter: u64) -> Self {
+        ReplayWindow {
+            highest: counter,
+            bitmap: 1,
+            reserved: counter.saturating_add(RESERVATION),
+            saved: None,
+        }
+    }
+
+    /// Window after a restart. If the reservation is ahead of the highest
+    /// saved counter the server crashed, and any counter up to the
+    /// reservation may have been accepted since.
+    fn reopen(mut self) -> Self {
+        if self.reserved > self.highest {
+            self.highest = self.reserved;
+            self.bitmap = u64::MAX;
+        }
+        self.saved = Some(self.reserved);
+        self
+    }
+
+    fn check(&self, counter: u64) -> Result<(), ReplayError> {
+        if counter > self.highest {
+            return Ok(());
+        }
+
+        let offset = self.highest - counter;
+        if offset >= WINDOW_SIZE {
+            return Err(ReplayError::Stale {
+                highest: self.highest,
+            });
+        }
+        if self.bitmap & (1 << offset) != 0 {
+            return Err(ReplayError::Duplicate);
+        }
+
+        Ok(())
+    }
+
+    fn record(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bitmap = if shift >= WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.highest = counter;
+        } else {
+            self.bitmap |= 1 << (self.highest - counter);
+        }
+
+        if counter > self.reserved {
+            self.reserved = counter.saturating_add(RESERVATION);
+        }
+    }
+
+    fn forget(&mut self, counter: u64) {
+        if let Some(offset) = self.highest.checked_sub(counter) {
+            if offset < WINDOW_SIZE {
+                self.bitmap &= !(1 << offset);
+            }
+        }
+    }
+}
+
+pub struct ReplayGuard {
+    path: PathBuf,
+    windows: Mutex<HashMap<String, ReplayWindow>>,
+    /// Whether the windows changed since they were last written.
+    dirty: AtomicBool,
+    /// Held while writing so an older snapshot never replaces a newer one.
+    flushing: Mutex<()>,
+}
+
+impl ReplayGuard {
+    /// Opens the replay state at `path`, starting empty if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let windows: HashMap<String, ReplayWindow> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        let windows = windows
+            .into_iter()
+            .map(|(sensor, window)| (sensor, window.reopen()))
+            .collect();
+
+        Ok(ReplayGuard {
+            path,
+            windows: Mutex::new(windows),
+            dirty: AtomicBool::new(false),
+            flushing: Mutex::new(()),
+        })
+    }
+
+    /// Accepts `counter` for `sensor` unless it was seen before or has fallen
+    /// out of the window.
+    ///
+    /// Only call this for frames that authenticated, otherwise forged frames
+    /// could push the window forward. The counter is only safe to acknowledge
+    /// once [`is_saved`](Self::is_saved), see [`admit`].
+    pub fn check_and_record(&self, sensor: &str, counter: u64) -> Result<(), ReplayError> {
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(sensor) {
+            Some(window) => {
+                window.check(counter)?;
+                window.record(counter);
+            }
+            None => {
+                windows.insert(sensor.to_owned(), ReplayWindow::new(counter));
+            }
+        }
+        self.dirty.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Whether the state file reserves `counter` for `sensor`, so a crash
+    /// can't accept it again.
+    pub fn is_saved(&self, sensor: &str, counter: u64) -> bool {
+        self.windows
+            .lock()
+            .unwrap()
+            .get(sensor)
+            .and_then(|window| window.saved)
+            .is_some_and(|saved| counter <= saved)
+    }
+
+    /// Accepts `counter` for `sensor` again, e.g. because its reading couldn't
+    /// be stored after [`check_and_record`](Self::check_and_record) let it
+    /// through. The window doesn't move back.
+    pub fn forget(&self, sensor: &str, counter: u64) {
+        if let Some(window) = self.windows.lock().unwrap().get_mut(sensor) {
+            window.forget(counter);
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Forgets the window of `sensor`, e.g. after it was deregistered.
+    pub fn reset(&self, sensor: &str) {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.remove(sensor).is_some() {
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Writes the windows to the state file if they changed since the last
+    /// flush. This blocks on disk I/O.
+    pub fn flush(&self) -> io::Result<()> {
+        let _flushing = self.flushing.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let windows = self.windows.lock().unwrap().clone();
+
+        let saved = self.save(&windows);
+        match saved {
+            Ok(()) => {
+                let mut current = self.windows.lock().unwrap();
+                for (sensor, window) in &windows {
+                    if let Some(current) = current.get_mut(sensor) {
+                        current.saved = current.saved.max(Some(window.reserved));
+                    }
+                }
+            }
+            Err(_) => self.dirty.store(true, Ordering::Release),
+        }
+        saved
+    }
+
+    /// Drops the reservations and writes the exact windows, so the next start
+    /// doesn't skip counters. Only call this once no more frames are checked.
+    pub fn close(&self) -> io::Result<()> {
+        for window in self.windows.lock().unwrap().values_mut() {
+            window.reserved = window.highest;
+        }
+        self.dirty.store(true, Ordering::Release);
+        self.flush()
+    }
+
+    fn save(&self, windows: &HashMap<String, ReplayWindow>) -> io::Result<()> {
+        let bytes = serde_json::to_vec(windows)?;
+
+        let parent = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(parent)?;
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = fs::File::create(&tmp_path)?;
+            tmp.write_all(&bytes)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        // make the rename itself durable
+        fs::File::open(parent)?.sync_all()
+    }
+}
+
+/// Flushes `guard` every `period` until `shutdown` is cancelled. The final
+/// flush after shutdown is left to the caller.
+pub async fn flush_periodically(
+    guard: Arc<ReplayGuard>,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        persist(&guard).await;
+    }
+}
+
+/// Accepts `counter` for `sensor` like [`ReplayGuard::check_and_record`],
+/// writing a new reservation first if the counter is past the saved one. A
+/// counter that can't be saved is forgotten again and [`ReplayError::Unsaved`]
+/// returned, so the frame isn't acknowledged.
+pub async fn admit(
+    guard: &Arc<ReplayGuard>,
+    sensor: &str,
+    counter: u64,
+) -> Result<(), ReplayError> {
+    guard.check_and_record(sensor, counter)?;
+    if guard.is_saved(sensor, counter) {
+        return Ok(());
+    }
+
+    let flushing = guard.clone();
+    let saved = match tokio::task::spawn_blocking(move || flushing.flush()).await {
+        Ok(saved) => saved,
+        Err(e) => Err(io::Error::other(e)),
+    };
+    match saved {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            event!(Level::ERROR, "Failed to reserve replay counters: {}", e);
+            guard.forget(sensor, counter);
+            Err(ReplayError::Unsaved)
+        }
+    }
+}
+
+/// Flushes `guard` on the blocking thread pool, logging failures.
+pub async fn persist(guard: &Arc<ReplayGuard>) {
+    run_blocking(guard, ReplayGuard::flush).await;
+}
+
+/// Writes the exact windows at shutdown, see [`ReplayGuard::close`].
+pub async fn close(guard: &Arc<ReplayGuard>) {
+    run_blocking(guard, ReplayGuard::close).await;
+}
+
+async fn run_blocking(guard: &Arc<ReplayGuard>, operation: fn(&ReplayGuard) -> io::Result<()>) {
+    let guard = guard.clone();
+    match tokio::task::spawn_blocking(move || operation(&guard)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => event!(Level::ERROR, "Failed to persist replay state: {}", e),
+        Err(e) => event!(Level::ERROR, "Replay state flush panicked: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let mut window = ReplayWindow::new(10);
+
+        assert_eq!(window.check(10), Err(ReplayError::Duplicate));
+        assert_eq!(window.check(11), Ok(()));
+
+        // out of order within the window
+        assert_eq!(window.check(5), Ok(()));
+        window.record(5);
+        assert_eq!(window.check(5), Err(ReplayError::Duplicate));
+
+        window.record(100);
+        assert_eq!(window.check(10), Err(ReplayError::Stale { highest: 100 }));
+        assert_eq!(
+            window.check(100 - WINDOW_SIZE),
+            Err(ReplayError::Stale { highest: 100 })
+        );
+        assert_eq!(window.check(100 - WINDOW_SIZE + 1), Ok(()));
+
+        // a jump larger than the window clears it
+        window.record(1000);
+        assert_eq!(window.bitmap, 1);
+        assert_eq!(window.check(999), Ok(()));
+
+        window.forget(1000);
+        assert_eq!(window.check(1000), Ok(()));
+        window.record(1000);
+        assert_eq!(window.check(1000), Err(ReplayError::Duplicate));
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.json");
+
+        let guard = ReplayGuard::open(&path).unwrap();
+        for counter in [1, 2, 4] {
+            guard.check_and_record("a", counter).unwrap();
+        }
+        guard.check_and_record("b", 7).unwrap();
+        // nothing is written until the guard is flushed
+        assert!(!path.exists());
+        guard.close().unwrap();
+
+        let guard = ReplayGuard::open(&path).unwrap();
+        assert_eq!(guard.check_and_record("a", 2), Err(ReplayError::Duplicate));
+        assert_eq!(guard.check_and_record("b", 7), Err(ReplayError::Duplicate));
+        assert_eq!(guard.check_and_record("a", 3), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn crash_skips_reserved_counters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.json");
+
+        let guard = Arc::new(ReplayGuard::open(&path).unwrap());
+        admit(&guard, "a", 1).await.unwrap();
+        // the reservation is saved before the counter is acknowledged
+        assert!(guard.is_saved("a", 1 + RESERVATION));
+        assert!(!guard.is_saved("a", 2 + RESERVATION));
+        for counter in 2..10 {
+            admit(&guard, "a", counter).await.unwrap();
+        }
+
+        // reopened without closing, as after a crash
+        let guard = Arc::new(ReplayGuard::open(&path).unwrap());
+        for counter in [9, 10, 1 + RESERVATION] {
+            assert!(admit(&guard, "a", counter).await.is_err());
+        }
+        admit(&guard, "a", 2 + RESERVATION).await.unwrap();
+    }
+
+    #[test]
+    fn reset_forgets_sensor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("replay.json");
+
+        let guard = ReplayGuard::open(&path).unwrap();
+        guard.check_and_record("a", 100).unwrap();
+        guard.check_and_record("b", 100).unwrap();
+        guard.flush().unwrap();
+        guard.reset("a");
+        guard.close().unwrap();
+
+        let guard = ReplayGuard::open(&path).unwrap();
+        assert_eq!(guard.check_and_record("a", 0), Ok(()));
+        assert_eq!(
+            guard.check_and_record("b", 0),
+            Err(ReplayError::Stale { highest: 100 })
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Validation of decrypted sensor payloads against the registered fields.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde_json::{Map, Value};
+
+use crate::storage::FieldValue;
+use crate::FieldType;
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    NotUtf8,
+    InvalidJson(String),
+    NotAnObject,
+    MissingField(String),
+    UnexpectedField(String),
+    WrongType { field: String, expected: FieldType },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::NotUtf8 => write!(f, "payload is not valid UTF-8"),
+            SchemaError::InvalidJson(e) => write!(f, "payload is not valid JSON: {}", e),
+            SchemaError::NotAnObject => write!(f, "payload is not a JSON object"),
+            SchemaError::MissingField(field) => write!(f, "missing field \"{}\"", field),
+            SchemaError::UnexpectedField(field) => write!(f, "unexpected field \"{}\"", field),
+            SchemaError::WrongType { field, expected } => {
+                write!(f, "field \"{}\" is not of type {:?}", field, expected)
+            }
+        }
+    }
+}
+
+/// Parses a decrypted payload and checks that it holds exactly the declared
+/// fields with their declared types.
+///
+/// Integer fields must be JSON integers, float fields accept any number so
+/// sensors may print whole numbers without a fraction.
+pub fn validate(
+    fields: &[String],
+    field_types: &[FieldType],
+    plaintext: &[u8],
+) -> Result<BTreeMap<String, FieldValue>, SchemaError> {
+    let Ok(text) = std::str::from_utf8(plaintext) else {
+        return Err(SchemaError::NotUtf8);
+    };
+    let object: Map<String, Value> = match serde_json::from_str(text) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(SchemaError::NotAnObject),
+        Err(e) => return Err(SchemaError::InvalidJson(e.to_string())),
+    };
+
+    if let Some(field) = object.keys().find(|key| !fields.contains(key)) {
+        return Err(SchemaError::UnexpectedField(field.clone()));
+    }
+
+    let mut values = BTreeMap::new();
+    for (field, field_type) in fields.iter().zip(field_types) {
+        let Some(value) = object.get(field) else {
+            return Err(SchemaError::MissingField(field.clone()));
+        };
+
+        let value = match field_type {
+            FieldType::Integer => value.as_i64().map(FieldValue::Integer),
+            FieldType::Float => value.as_f64().map(FieldValue::Float),
+        };
+        let Some(value) = value else {
+            return Err(SchemaError::WrongType {
+                field: field.clone(),
+                expected: *field_type,
+            });
+        };
+
+        values.insert(field.clone(), value);
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn schema() -> (Vec<String>, Vec<FieldType>) {
+        (
+            vec!["x".to_owned(), "y".to_owned()],
+            vec![FieldType::Integer, FieldType::Float],
+        )
+    }
+
+    #[test]
+    fn valid_payloads() {
+        let (fields, field_types) = schema();
+
+        let values = validate(&fields, &field_types, br#"{"x": -3, "y": 0.5}"#).unwrap();
+        assert_eq!(values["x"], FieldValue::Integer(-3));
+        assert_eq!(values["y"], FieldValue::Float(0.5));
+
+        // whole numbers are fine for float fields
+        let values = validate(&fields, &field_types, br#"{"y": 2, "x": 1}"#).unwrap();
+        assert_eq!(values["y"], FieldValue::Float(2.0));
+    }
+
+    #[test]
+    fn invalid_payloads() {
+        let (fields, field_types) = schema();
+        let check = |payload: &[u8]| validate(&fields, &field_types, payload).unwrap_err();
+
+        assert_eq!(check(&[0xff, 0xfe]), SchemaError::NotUtf8);
+        assert!(matches!(check(b"{\"x\": "), SchemaError::InvalidJson(_)));
+        assert_eq!(check(b"[1, 2]"), SchemaError::NotAnObject);
+        assert_eq!(
+            check(br#"{"x": 1}"#),
+            SchemaError::MissingField("y".to_owned())
+        );
+        assert_eq!(
+            check(br#"{"x": 1, "y": 2.0, "z": 3}"#),
+            SchemaError::UnexpectedField("z".to_owned())
+        );
+        assert_eq!(
+            check(br#"{"x": 1.5, "y": 2.0}"#),
+            SchemaError::WrongType {
+                field: "x".to_owned(),
+                expected: FieldType::Integer
+            }
+        );
+        assert_eq!(
+            check(br#"{"x": 1, "y": "2.0"}"#),
+            SchemaError::WrongType {
+                field: "y".to_owned(),
+                expected: FieldType::Float
+            }
+        );
+    }
+}
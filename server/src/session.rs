@@ -0,0 +1,124 @@
+//! Short-lived bearer tokens issued after a challenge-response login.
+//!
+//! A token is `base64url(claims) "." base64url(HMAC-SHA256(claims))`, where
+//! the claims are a small JSON object naming the user and the expiry time.
+//! The MAC key is generated on start, so a restart logs everyone out.
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+
+use crate::storage::now_millis;
+
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(15 * 60);
+
+const MAC_KEY_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    user: String,
+    /// Unix time in seconds after which the token is no longer accepted.
+    expires_at: u64,
+}
+
+pub struct SessionSigner {
+    key: [u8; MAC_KEY_SIZE],
+    ttl: Duration,
+}
+
+impl SessionSigner {
+    pub fn new(ttl: Duration) -> Self {
+        let mut key = [0; MAC_KEY_SIZE];
+        rand::thread_rng().fill(&mut key);
+
+        SessionSigner { key, ttl }
+    }
+
+    /// Issues a token for `user`, returning it with its expiry time.
+    pub fn issue(&self, user: &str) -> (String, u64) {
+        let claims = Claims {
+            user: user.to_owned(),
+            expires_at: now_millis() / 1000 + self.ttl.as_secs(),
+        };
+        let claims_json = serde_json::to_vec(&claims).unwrap();
+
+        let mut mac = self.mac();
+        mac.update(&claims_json);
+        let tag = mac.finalize().into_bytes();
+
+        let token = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(&claims_json),
+            BASE64_URL_SAFE_NO_PAD.encode(tag)
+        );
+        (token, claims.expires_at)
+    }
+
+    /// Returns the user a token was issued to if it is authentic and has
+    /// not expired.
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let (claims_part, tag_part) = token.split_once('.')?;
+        let claims_json = BASE64_URL_SAFE_NO_PAD.decode(claims_part).ok()?;
+        let tag = BASE64_URL_SAFE_NO_PAD.decode(tag_part).ok()?;
+
+        let mut mac = self.mac();
+        mac.update(&claims_json);
+        mac.verify_slice(&tag).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&claims_json).ok()?;
+        if now_millis() / 1000 >= claims.expires_at {
+            return None;
+        }
+
+        Some(claims.user)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let signer = SessionSigner::new(DEFAULT_SESSION_TTL);
+        let (token, expires_at) = signer.issue("testUser");
+
+        assert_eq!(signer.verify(&token), Some("testUser".to_owned()));
+        assert!(expires_at > now_millis() / 1000);
+
+        // tokens from another server instance are rejected
+        assert_eq!(SessionSigner::new(DEFAULT_SESSION_TTL).verify(&token), None);
+    }
+
+    #[test]
+    fn tampered_tokens() {
+        let signer = SessionSigner::new(DEFAULT_SESSION_TTL);
+        let (token, _) = signer.issue("testUser");
+        let (_, tag) = token.split_once('.').unwrap();
+
+        let forged_claims = BASE64_URL_SAFE_NO_PAD.encode(format!(
+            "{{\"user\":\"admin\",\"expires_at\":{}}}",
+            u64::MAX
+        ));
+        assert_eq!(signer.verify(&format!("{}.{}", forged_claims, tag)), None);
+        assert_eq!(signer.verify(&token.replace('.', "")), None);
+        assert_eq!(signer.verify("not a token"), None);
+    }
+
+    #[test]
+    fn expired_tokens() {
+        let signer = SessionSigner::new(Duration::ZERO);
+        let (token, _) = signer.issue("testUser");
+
+        assert_eq!(signer.verify(&token), None);
+    }
+}
@@ -0,0 +1,795 @@
+//! Durable storage of decrypted sensor readings.
+//!
+//! Readings are kept behind the [`ReadingStore`] trait. The default
+//! implementation, [`SegmentLog`], appends readings to segment files in a
+//! directory and keeps a small index file next to every segment so the store
+//! can be reopened without parsing every record. Only a sparse summary of
+//! every index is kept in memory, queries read the index files themselves.
+//!
+//! Store operations block on disk I/O, async code runs them through
+//! [`blocking`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+const LOG_EXTENSION: &str = "log";
+const INDEX_EXTENSION: &str = "idx";
+const RECORD_HEADER_SIZE: u64 = 4;
+const MAX_RECORD_SIZE: usize = 1024 * 1024;
+/// Index entries between two checkpoints of a segment summary.
+const CHECKPOINT_INTERVAL: u64 = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Integer(i64),
+    Float(f64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub sensor: String,
+    pub counter: u64,
+    /// Server receive time in milliseconds since the unix epoch.
+    pub received_at: u64,
+    pub values: BTreeMap<String, FieldValue>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoredReading {
+    /// Position of the reading in the store, increases with every append.
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub reading: Reading,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    #[serde(alias = "asc")]
+    Ascending,
+    #[serde(alias = "desc")]
+    Descending,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadingQuery {
+    pub sensor: String,
+    /// Inclusive lower bound on `received_at`.
+    pub from: Option<u64>,
+    /// Exclusive upper bound on `received_at`.
+    pub to: Option<u64>,
+    /// Only return readings after this sequence number, in query order.
+    pub after: Option<u64>,
+    pub limit: usize,
+    pub order: Order,
+}
+
+impl ReadingQuery {
+    /// Whether the reading with `sequence` comes after the cursor in query
+    /// order.
+    fn is_past_cursor(&self, sequence: u64) -> bool {
+        match (self.order, self.after) {
+            (_, None) => true,
+            (Order::Ascending, Some(after)) => sequence > after,
+            (Order::Descending, Some(after)) => sequence < after,
+        }
+    }
+}
+
+pub trait ReadingStore: Send + Sync {
+    /// Stores a reading and returns its sequence number.
+    fn append(&self, reading: &Reading) -> io::Result<u64>;
+
+    fn query(&self, query: &ReadingQuery) -> io::Result<Vec<StoredReading>>;
+
+    /// Makes every appended reading durable.
+    fn flush(&self) -> io::Result<()>;
+}
+
+/// Runs `operation` on the blocking thread pool, so a slow disk doesn't stall
+/// the async runtime.
+pub async fn blocking<T, F>(store: &Arc<dyn ReadingStore>, operation: F) -> io::Result<T>
+where
+    F: FnOnce(&dyn ReadingStore) -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || operation(&*store))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    sequence: u64,
+    received_at: u64,
+    segment: u64,
+    offset: u64,
+}
+
+struct ActiveSegment {
+    id: u64,
+    log: File,
+    index: File,
+    len: u64,
+}
+
+/// First and end sequence number and index file positions of the entries
+/// between two checkpoints.
+type Window = (u64, u64, u64, u64);
+
+/// Sparse in-memory summary of a segment's index file.
+#[derive(Debug, Default)]
+struct SegmentSummary {
+    id: u64,
+    /// Sequence number after the last reading of the segment.
+    end: u64,
+    /// Length of the index file.
+    index_len: u64,
+    /// Lowest and highest receive time of every sensor in the segment.
+    sensors: HashMap<String, (u64, u64)>,
+    /// Sequence number and index file position of every
+    /// [`CHECKPOINT_INTERVAL`]th entry, starting with the first.
+    checkpoints: Vec<(u64, u64)>,
+}
+
+impl SegmentSummary {
+    fn new(id: u64) -> Self {
+        SegmentSummary {
+            id,
+            end: id,
+            ..Default::default()
+        }
+    }
+
+    fn record(&mut self, sensor: &str, entry: &IndexEntry, entry_len: u64) {
+        if (self.end - self.id).is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.checkpoints.push((entry.sequence, self.index_len));
+        }
+        self.end = entry.sequence + 1;
+        self.index_len += entry_len;
+
+        match self.sensors.get_mut(sensor) {
+            Some((first, last)) => {
+                *first = (*first).min(entry.received_at);
+                *last = (*last).max(entry.received_at);
+            }
+            None => {
+                self.sensors
+                    .insert(sensor.to_owned(), (entry.received_at, entry.received_at));
+            }
+        }
+    }
+
+    /// Whether the segment may hold readings matching `query`.
+    fn may_match(&self, query: &ReadingQuery) -> bool {
+        let Some((first, last)) = self.sensors.get(&query.sensor) else {
+            return false;
+        };
+        let nearest = match query.order {
+            Order::Ascending => self.end - 1,
+            Order::Descending => self.id,
+        };
+
+        query.is_past_cursor(nearest)
+            && query.from.is_none_or(|from| *last >= from)
+            && query.to.is_none_or(|to| *first < to)
+    }
+
+    fn windows(&self) -> Vec<Window> {
+        let mut windows = Vec::with_capacity(self.checkpoints.len());
+        for (i, (sequence, position)) in self.checkpoints.iter().enumerate() {
+            let (end_sequence, end_position) = self
+                .checkpoints
+                .get(i + 1)
+                .copied()
+                .unwrap_or((self.end, self.index_len));
+            windows.push((*sequence, end_sequence, *position, end_position));
+        }
+
+        windows
+    }
+}
+
+struct SegmentLogInner {
+    segments: Vec<SegmentSummary>,
+    active: ActiveSegment,
+}
+
+/// Append-only, segmented on-disk [`ReadingStore`].
+///
+/// Every record in a `.log` segment is a little endian `u32` length followed
+/// by the JSON encoded reading. Each entry in the matching `.idx` file holds
+/// the sequence number, receive time and record offset as little endian
+/// `u64`s followed by the length prefixed sensor name. Segments are named
+/// after the first sequence number they contain.
+pub struct SegmentLog {
+    dir: PathBuf,
+    segment_size: u64,
+    inner: Mutex<SegmentLogInner>,
+}
+
+impl SegmentLog {
+    pub fn open(dir: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(LOG_EXTENSION) {
+                continue;
+            }
+            let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            segments.push(id);
+        }
+        segments.sort_unstable();
+
+        let mut summaries = Vec::with_capacity(segments.len());
+        for id in &segments {
+            let mut summary = SegmentSummary::new(*id);
+            for (name, entry) in load_segment(&dir, *id)? {
+                summary.record(&name, &entry, index_entry_len(&name));
+            }
+            summaries.push(summary);
+        }
+
+        let active_id = segments.last().copied().unwrap_or(0);
+        let active = open_segment(&dir, active_id)?;
+        if summaries.is_empty() {
+            summaries.push(SegmentSummary::new(active_id));
+        }
+
+        event!(
+            Level::INFO,
+            "Opened reading store {} with {} segments and {} readings",
+            dir.display(),
+            segments.len(),
+            summaries.last().map_or(0, |s| s.end)
+        );
+
+        Ok(SegmentLog {
+            dir,
+            segment_size,
+            inner: Mutex::new(SegmentLogInner {
+                segments: summaries,
+                active,
+            }),
+        })
+    }
+
+    /// Reads the index entries of `sensor` in `segment` between two file
+    /// positions.
+    fn read_index(
+        &self,
+        sensor: &str,
+        segment: u64,
+        start: u64,
+        end: u64,
+    ) -> io::Result<Vec<IndexEntry>> {
+        let mut index = File::open(segment_path(&self.dir, segment, INDEX_EXTENSION))?;
+        index.seek(SeekFrom::Start(start))?;
+        let mut bytes = vec![0u8; (end - start) as usize];
+        index.read_exact(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+        while let Some((name, entry, len)) = decode_index_entry(&bytes[position..], segment) {
+            if name == sensor {
+                entries.push(entry);
+            }
+            position += len;
+        }
+        if position != bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupt index entry",
+            ));
+        }
+
+        Ok(entries)
+    }
+
+    fn read_record(&self, entry: &IndexEntry) -> io::Result<Reading> {
+        let mut log = File::open(segment_path(&self.dir, entry.segment, LOG_EXTENSION))?;
+        log.seek(SeekFrom::Start(entry.offset))?;
+        let record = read_record(&mut log)?;
+
+        serde_json::from_slice(&record).map_err(io::Error::other)
+    }
+}
+
+impl ReadingStore for SegmentLog {
+    fn append(&self, reading: &Reading) -> io::Result<u64> {
+        if reading.sensor.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sensor name too long",
+            ));
+        }
+        let record = serde_json::to_vec(reading).map_err(io::Error::other)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let sequence = inner.segments.last().map_or(0, |s| s.end);
+
+        if inner.active.len >= self.segment_size {
+            // flush only syncs the active segment, so the one rolled off is
+            // synced here
+            inner.active.log.sync_data()?;
+            inner.active.index.sync_data()?;
+            inner.active = open_segment(&self.dir, sequence)?;
+            inner.segments.push(SegmentSummary::new(sequence));
+        }
+
+        let mut bytes = Vec::with_capacity(record.len() + RECORD_HEADER_SIZE as usize);
+        bytes.extend((record.len() as u32).to_le_bytes());
+        bytes.extend(&record);
+
+        let entry = IndexEntry {
+            sequence,
+            received_at: reading.received_at,
+            segment: inner.active.id,
+            offset: inner.active.len,
+        };
+        let index_entry = encode_index_entry(&reading.sensor, &entry);
+
+        // the index is written after the record so it never points past the
+        // log, and a failed write is cut off again so the next append doesn't
+        // land behind a torn record or reuse the sequence number
+        let summary = inner.segments.last_mut().unwrap();
+        let active = &mut inner.active;
+        let written = active
+            .log
+            .write_all(&bytes)
+            .and_then(|_| active.index.write_all(&index_entry));
+        if let Err(e) = written {
+            if let Err(truncate_error) = active
+                .log
+                .set_len(active.len)
+                .and_then(|_| active.index.set_len(summary.index_len))
+            {
+                event!(
+                    Level::ERROR,
+                    "Failed to cut off a partial append to segment {}: {}",
+                    active.id,
+                    truncate_error
+                );
+            }
+            return Err(e);
+        }
+        active.len += bytes.len() as u64;
+        summary.record(&reading.sensor, &entry, index_entry.len() as u64);
+
+        Ok(sequence)
+    }
+
+    fn query(&self, query: &ReadingQuery) -> io::Result<Vec<StoredReading>> {
+        let mut segments: Vec<(u64, Vec<Window>)> = {
+            let inner = self.inner.lock().unwrap();
+            inner
+                .segments
+                .iter()
+                .filter(|segment| segment.may_match(query))
+                .map(|segment| (segment.id, segment.windows()))
+                .collect()
+        }; // lock dropped before touching the segment files
+        if query.order == Order::Descending {
+            segments.reverse();
+        }
+
+        let in_range = |entry: &IndexEntry| {
+            query.from.is_none_or(|from| entry.received_at >= from)
+                && query.to.is_none_or(|to| entry.received_at < to)
+        };
+
+        let mut matches = Vec::new();
+        'segments: for (segment, windows) in &mut segments {
+            if query.order == Order::Descending {
+                windows.reverse();
+            }
+
+            for &mut (first, end, start_position, end_position) in windows {
+                // skip windows entirely on the wrong side of the cursor
+                let nearest = match query.order {
+                    Order::Ascending => end - 1,
+                    Order::Descending => first,
+                };
+                if !query.is_past_cursor(nearest) {
+                    continue;
+                }
+
+                let mut entries =
+                    self.read_index(&query.sensor, *segment, start_position, end_position)?;
+                if query.order == Order::Descending {
+                    entries.reverse();
+                }
+                for entry in entries {
+                    if query.is_past_cursor(entry.sequence) && in_range(&entry) {
+                        matches.push(entry);
+                        if matches.len() >= query.limit {
+                            break 'segments;
+                        }
+                    }
+                }
+            }
+        }
+
+        matches
+            .iter()
+            .map(|entry| {
+                Ok(StoredReading {
+                    sequence: entry.sequence,
+                    reading: self.read_record(entry)?,
+                })
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let inner = self.inner.lock().unwrap();
+        inner.active.log.sync_data()?;
+        inner.active.index.sync_data()
+    }
+}
+
+fn segment_path(dir: &Path, id: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, extension))
+}
+
+fn open_segment(dir: &Path, id: u64) -> io::Result<ActiveSegment> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, id, LOG_EXTENSION))?;
+    let index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, id, INDEX_EXTENSION))?;
+    let len = log.metadata()?.len();
+
+    Ok(ActiveSegment {
+        id,
+        log,
+        index,
+        len,
+    })
+}
+
+fn read_record(log: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; RECORD_HEADER_SIZE as usize];
+    log.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record too large",
+        ));
+    }
+    let mut record = vec![0u8; len];
+    log.read_exact(&mut record)?;
+
+    Ok(record)
+}
+
+fn index_entry_len(sensor: &str) -> u64 {
+    25 + sensor.len() as u64
+}
+
+fn encode_index_entry(sensor: &str, entry: &IndexEntry) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(index_entry_len(sensor) as usize);
+    bytes.extend(entry.sequence.to_le_bytes());
+    bytes.extend(entry.received_at.to_le_bytes());
+    bytes.extend(entry.offset.to_le_bytes());
+    bytes.push(sensor.len() as u8);
+    bytes.extend(sensor.as_bytes());
+
+    bytes
+}
+
+/// Loads the index of a segment, repairing it if the server stopped between
+/// writing a record and its index entry.
+fn load_segment(dir: &Path, id: u64) -> io::Result<Vec<(String, IndexEntry)>> {
+    let log_path = segment_path(dir, id, LOG_EXTENSION);
+    let index_path = segment_path(dir, id, INDEX_EXTENSION);
+    let log_len = fs::metadata(&log_path)?.len();
+    let index_bytes = fs::read(&index_path).unwrap_or_default();
+
+    let mut entries = Vec::new();
+    let mut position = 0;
+    while let Some((name, entry, len)) = decode_index_entry(&index_bytes[position..], id) {
+        if entry.offset >= log_len {
+            break;
+        }
+        entries.push((name.to_owned(), entry));
+        position += len;
+    }
+
+    let mut repaired = position != index_bytes.len();
+    let mut log = File::open(&log_path)?;
+
+    // find the end of the last complete indexed record
+    let mut offset = 0;
+    while let Some((_, entry)) = entries.last() {
+        log.seek(SeekFrom::Start(entry.offset))?;
+        if let Ok(record) = read_record(&mut log) {
+            offset = entry.offset + RECORD_HEADER_SIZE + record.len() as u64;
+            break;
+        }
+        entries.pop();
+        repaired = true;
+    }
+
+    // scan any records that are missing from the index
+    let mut next_sequence = entries.last().map(|(_, e)| e.sequence + 1).unwrap_or(id);
+    log.seek(SeekFrom::Start(offset))?;
+    while offset < log_len {
+        let Ok(record) = read_record(&mut log) else {
+            break;
+        };
+        let Ok(reading) = serde_json::from_slice::<Reading>(&record) else {
+            break;
+        };
+        let entry = IndexEntry {
+            sequence: next_sequence,
+            received_at: reading.received_at,
+            segment: id,
+            offset,
+        };
+        entries.push((reading.sensor, entry));
+        offset += RECORD_HEADER_SIZE + record.len() as u64;
+        next_sequence += 1;
+        repaired = true;
+    }
+
+    if offset < log_len {
+        event!(
+            Level::WARN,
+            "Discarding {} bytes of incomplete records from {}",
+            log_len - offset,
+            log_path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(offset)?;
+    }
+
+    if repaired {
+        event!(Level::WARN, "Rebuilding index {}", index_path.display());
+        let mut index_bytes = Vec::new();
+        for (name, entry) in &entries {
+            index_bytes.extend(encode_index_entry(name, entry));
+        }
+        fs::write(&index_path, index_bytes)?;
+    }
+
+    Ok(entries)
+}
+
+fn decode_index_entry(bytes: &[u8], segment: u64) -> Option<(&str, IndexEntry, usize)> {
+    if bytes.len() < 25 {
+        return None;
+    }
+    let name_len = bytes[24] as usize;
+    let name = std::str::from_utf8(bytes.get(25..25 + name_len)?).ok()?;
+
+    let entry = IndexEntry {
+        sequence: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+        received_at: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        segment,
+        offset: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+    };
+
+    Some((name, entry, 25 + name_len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reading(sensor: &str, counter: u64, received_at: u64) -> Reading {
+        let mut values = BTreeMap::new();
+        values.insert("x".to_owned(), FieldValue::Integer(counter as i64));
+        values.insert("y".to_owned(), FieldValue::Float(0.5));
+
+        Reading {
+            sensor: sensor.to_owned(),
+            counter,
+            received_at,
+            values,
+        }
+    }
+
+    fn query(sensor: &str) -> ReadingQuery {
+        ReadingQuery {
+            sensor: sensor.to_owned(),
+            from: None,
+            to: None,
+            after: None,
+            limit: usize::MAX,
+            order: Order::Ascending,
+        }
+    }
+
+    #[test]
+    fn append_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+
+        for i in 0..10 {
+            store.append(&reading("a", i, 1000 + i)).unwrap();
+            store.append(&reading("b", i, 1000 + i)).unwrap();
+        }
+
+        let readings = store.query(&query("a")).unwrap();
+        assert_eq!(readings.len(), 10);
+        assert_eq!(readings[3].reading, reading("a", 3, 1003));
+        assert_eq!(readings[3].sequence, 6);
+
+        let mut q = query("b");
+        q.from = Some(1002);
+        q.to = Some(1005);
+        let counters: Vec<u64> = store
+            .query(&q)
+            .unwrap()
+            .iter()
+            .map(|r| r.reading.counter)
+            .collect();
+        assert_eq!(counters, vec![2, 3, 4]);
+
+        assert!(store.query(&query("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pagination_and_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        for i in 0..5 {
+            store.append(&reading("a", i, i)).unwrap();
+        }
+
+        let mut q = query("a");
+        q.limit = 2;
+        let page = store.query(&q).unwrap();
+        assert_eq!(page.len(), 2);
+        q.after = Some(page[1].sequence);
+        assert_eq!(store.query(&q).unwrap()[0].reading.counter, 2);
+
+        let mut q = query("a");
+        q.order = Order::Descending;
+        q.after = Some(3);
+        let counters: Vec<u64> = store
+            .query(&q)
+            .unwrap()
+            .iter()
+            .map(|r| r.reading.counter)
+            .collect();
+        assert_eq!(counters, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn pages_through_sparse_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SegmentLog::open(dir.path(), 256 * 1024).unwrap();
+        let count = 3 * CHECKPOINT_INTERVAL;
+        for i in 0..count {
+            store.append(&reading("a", i, i)).unwrap();
+            store.append(&reading("b", i, i)).unwrap();
+        }
+        {
+            let inner = store.inner.lock().unwrap();
+            assert!(inner.segments.len() > 1);
+            let checkpoints: usize = inner.segments.iter().map(|s| s.checkpoints.len()).sum();
+            assert!(checkpoints < 2 * count as usize / 100);
+        }
+
+        for order in [Order::Ascending, Order::Descending] {
+            let mut q = query("b");
+            q.order = order;
+            q.limit = 100;
+            let mut counters = Vec::new();
+            loop {
+                let page = store.query(&q).unwrap();
+                let Some(last) = page.last() else {
+                    break;
+                };
+                q.after = Some(last.sequence);
+                counters.extend(page.iter().map(|r| r.reading.counter));
+            }
+
+            let mut expected: Vec<u64> = (0..count).collect();
+            if order == Order::Descending {
+                expected.reverse();
+            }
+            assert_eq!(counters, expected);
+        }
+
+        let mut q = query("a");
+        q.from = Some(2000);
+        q.to = Some(2003);
+        let counters: Vec<u64> = store
+            .query(&q)
+            .unwrap()
+            .iter()
+            .map(|r| r.reading.counter)
+            .collect();
+        assert_eq!(counters, vec![2000, 2001, 2002]);
+    }
+
+    #[test]
+    fn survives_reopen_and_rolls_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = SegmentLog::open(dir.path(), 256).unwrap();
+            for i in 0..20 {
+                store.append(&reading("a", i, i)).unwrap();
+            }
+            store.flush().unwrap();
+        }
+
+        let segments = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == LOG_EXTENSION)
+            .count();
+        assert!(segments > 1);
+
+        let store = SegmentLog::open(dir.path(), 256).unwrap();
+        assert_eq!(store.query(&query("a")).unwrap().len(), 20);
+        assert_eq!(store.append(&reading("a", 20, 20)).unwrap(), 20);
+    }
+
+    #[test]
+    fn recovers_from_torn_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+            for i in 0..3 {
+                store.append(&reading("a", i, i)).unwrap();
+            }
+        }
+
+        // lose the last index entry and half of a new record
+        let index_path = segment_path(dir.path(), 0, INDEX_EXTENSION);
+        let index = fs::read(&index_path).unwrap();
+        fs::write(&index_path, &index[..index.len() - 10]).unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 0, LOG_EXTENSION))
+            .unwrap();
+        log.write_all(&[200, 0, 0, 0, b'{']).unwrap();
+
+        let store = SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap();
+        let readings = store.query(&query("a")).unwrap();
+        assert_eq!(readings.len(), 3);
+        assert_eq!(readings[2].reading, reading("a", 2, 2));
+        assert_eq!(store.append(&reading("a", 3, 3)).unwrap(), 3);
+        assert_eq!(store.query(&query("a")).unwrap().len(), 4);
+    }
+}
@@ -1,182 +1,994 @@
+use crate::frame::{Ack, AckStatus, Frame, FrameCodec, FrameError};
+use crate::metrics::Metrics;
+use crate::replay::{self, ReplayError, ReplayGuard};
+use crate::storage::{self, now_millis, Reading, ReadingStore, StoredReading};
+use crate::tls::TlsListener;
 use crate::Sensor;
 use aes::Aes128;
+use axum::serve::Listener;
 use ccm::aead::generic_array::GenericArray;
 use ccm::aead::Aead;
 use ccm::consts::{U13, U4};
 use ccm::{Ccm, KeyInit};
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, RwLock};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{event, instrument, Level};
 
 pub type Aes128Ccm = Ccm<Aes128, U4, U13>;
 
+pub const DEFAULT_ERROR_BUDGET: ErrorBudget = ErrorBudget {
+    burst: 500,
+    per_sec: 50,
+};
+/// Frames from unknown sensors are reported at most this often per connection.
+const UNKNOWN_SENSOR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State the data listener shares with the rest of the process.
+#[derive(Clone)]
+pub struct Services {
+    pub sensors: Arc<RwLock<HashMap<String, Sensor>>>,
+    pub store: Arc<dyn ReadingStore>,
+    pub replay: Arc<ReplayGuard>,
+    pub live: broadcast::Sender<Arc<StoredReading>>,
+    pub error_budget: ErrorBudget,
+    pub metrics: Arc<Metrics>,
+}
+
+/// Invalid frames, frames from unknown sensors and frames that fail to
+/// decrypt a connection may send before it is closed: a burst of `burst`,
+/// refilled at `per_sec` a second. A gateway with a few misconfigured sensors
+/// stays well within it, a peer sending mostly junk does not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorBudget {
+    pub burst: u32,
+    pub per_sec: u32,
+}
+
+/// Errors a peer is charged for against its [`ErrorBudget`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PeerError {
+    InvalidFrame,
+    UnknownSensor,
+    DecryptFailure,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::InvalidFrame => write!(f, "an invalid frame"),
+            PeerError::UnknownSensor => write!(f, "a frame from an unknown sensor"),
+            PeerError::DecryptFailure => write!(f, "a frame that failed to decrypt"),
+        }
+    }
+}
+
+/// What is left of a connection's [`ErrorBudget`].
+struct ErrorAllowance {
+    budget: ErrorBudget,
+    remaining: f64,
+    refilled_at: Instant,
+    invalid_frames: u64,
+    unknown_sensor_frames: u64,
+    decrypt_failures: u64,
+}
+
+impl ErrorAllowance {
+    fn new(budget: ErrorBudget) -> Self {
+        ErrorAllowance {
+            budget,
+            remaining: budget.burst as f64,
+            refilled_at: Instant::now(),
+            invalid_frames: 0,
+            unknown_sensor_frames: 0,
+            decrypt_failures: 0,
+        }
+    }
+
+    /// Takes one error from the budget, false once it is used up.
+    fn charge(&mut self, error: PeerError) -> bool {
+        match error {
+            PeerError::InvalidFrame => self.invalid_frames += 1,
+            PeerError::UnknownSensor => self.unknown_sensor_frames += 1,
+            PeerError::DecryptFailure => self.decrypt_failures += 1,
+        }
+
+        let now = Instant::now();
+        let refill =
+            now.duration_since(self.refilled_at).as_secs_f64() * self.budget.per_sec as f64;
+        self.remaining = (self.remaining + refill).min(self.budget.burst as f64);
+        self.refilled_at = now;
+
+        if self.remaining < 1.0 {
+            return false;
+        }
+        self.remaining -= 1.0;
+        true
+    }
+
+    fn log_exceeded(&self, socket: SocketAddr, last: PeerError) {
+        event!(
+            Level::WARN,
+            "{} used up its error budget on {} after {} invalid frames, {} frames from unknown sensors and {} decrypt failures. Closing connection",
+            socket,
+            last,
+            self.invalid_frames,
+            self.unknown_sensor_frames,
+            self.decrypt_failures
+        );
+    }
+}
+
+/// Frames from unknown sensors skipped on one connection.
+#[derive(Default)]
+struct UnknownSensors {
+    skipped: u64,
+    unreported: u64,
+    reported_at: Option<Instant>,
+}
+
+impl UnknownSensors {
+    /// Counts a skipped frame, logging at most once per
+    /// [`UNKNOWN_SENSOR_LOG_INTERVAL`].
+    fn record(&mut self, name: &str, socket: SocketAddr) {
+        self.skipped += 1;
+        self.unreported += 1;
+        if self
+            .reported_at
+            .is_some_and(|at| at.elapsed() < UNKNOWN_SENSOR_LOG_INTERVAL)
+        {
+            return;
+        }
+
+        event!(
+            Level::WARN,
+            "Skipped {} frames from unknown sensors on {}, the latest from \"{}\". {} skipped so far",
+            self.unreported,
+            socket,
+            name,
+            self.skipped
+        );
+        self.unreported = 0;
+        self.reported_at = Some(Instant::now());
+    }
+}
+
+/// Accepts sensor connections, over TLS if an acceptor is given, until
+/// `shutdown` is cancelled. Returns once every connection has closed.
 #[instrument(skip_all)]
-pub async fn serve(data_listener: TcpListener, sensors: Arc<RwLock<HashMap<String, Sensor>>>) {
-    loop {
-        match data_listener.accept().await {
-            Ok((stream, socket)) => {
-                event!(Level::INFO, "Accepting TCP connection: {}", socket);
-                tokio::spawn(handle_data_client(stream, socket, sensors.clone()));
-            }
-            Err(e) => {
-                event!(Level::ERROR, "TCP connection error: {}", e);
-            }
+pub async fn serve(
+    data_listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    codec: FrameCodec,
+    services: Services,
+    shutdown: CancellationToken,
+) {
+    match tls {
+        Some(acceptor) => {
+            let listener = TlsListener::new(data_listener, acceptor)
+                .expect("Couldn't set up TLS on the data listener");
+            accept_clients(listener, codec, services, shutdown).await
         }
+        None => accept_clients(data_listener, codec, services, shutdown).await,
+    }
+}
+
+async fn accept_clients<L: Listener<Addr = SocketAddr>>(
+    mut data_listener: L,
+    codec: FrameCodec,
+    services: Services,
+    shutdown: CancellationToken,
+) {
+    let connections = TaskTracker::new();
+
+    loop {
+        let (stream, socket) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = data_listener.accept() => accepted,
+        };
+        event!(Level::INFO, "Accepting TCP connection: {}", socket);
+        services.metrics.connection_accepted();
+        connections.spawn(handle_data_client(
+            stream,
+            socket,
+            codec,
+            services.clone(),
+            shutdown.clone(),
+        ));
     }
+
+    drop(data_listener);
+    connections.close();
+    event!(
+        Level::INFO,
+        "Stopped accepting data connections, waiting for {} to close",
+        connections.len()
+    );
+    connections.wait().await;
 }
 
 #[instrument(skip_all)]
-async fn handle_data_client(
-    stream: TcpStream,
+async fn handle_data_client<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
     socket: SocketAddr,
-    sensors: Arc<RwLock<HashMap<String, Sensor>>>,
+    mut codec: FrameCodec,
+    services: Services,
+    shutdown: CancellationToken,
 ) {
-    let (rx, tx) = stream.into_split();
+    let (rx, tx) = io::split(stream);
 
     let mut reader = BufReader::new(rx);
-    let _writer = BufWriter::new(tx);
+    let mut writer = BufWriter::new(tx);
+    let mut allowance = ErrorAllowance::new(services.error_budget);
+    let mut unknown_sensors = UnknownSensors::default();
 
     loop {
         event!(Level::DEBUG, "starting main loop");
-        // read until start of known protocol
-        let mut start: Vec<u8> = Vec::new();
-        let Ok(_) = reader.read_until(b'>', &mut start).await else {
-            event!(
-                Level::WARN,
-                "Failed to find data transfer sensor data protocol start. Closing connection: {}",
-                socket
-            );
-            return;
-        };
-        if start.len() > 1 {
-            event!(
-                Level::INFO,
-                "Read {} bytes without finding sensor data protocol start",
-                start.len() - 1
-            );
-        } else {
-            event!(
-                Level::INFO,
-                "Connection closed while finding protocol beginning"
-            );
-            return;
+        // shutdown only cuts the wait for the next frame short, one that
+        // started arriving is read and handled before closing
+        tokio::select! {
+            _ = reader.fill_buf() => {}
+            _ = shutdown.cancelled() => {
+                event!(Level::INFO, "Closing connection {} for shutdown", socket);
+                return;
+            }
         }
-        start.clear();
-        event!(Level::DEBUG, "protocol start found");
+        let frame = codec.read_frame(&mut reader).await;
 
-        // read sensor name
-        let Ok(len) = reader.read_until(b'<', &mut start).await else {
-            event!(
-                Level::WARN,
-                "Failed to find end of sensor name. Closing connection: {}",
-                socket
-            );
-            return;
-        };
-        if len == 0 {
+        let skipped = codec.take_skipped();
+        if skipped > 0 {
+            services.metrics.skipped_bytes(skipped);
             event!(
                 Level::INFO,
-                "Connection closed while finding end of sensor name"
+                "Read {} bytes without finding sensor data protocol start",
+                skipped
             );
-            return;
         }
-        event!(Level::DEBUG, "end of sensor name found");
-
-        // decode sensor name
-        start.pop().unwrap();
-        let Ok(name) = String::from_utf8(start) else {
-            event!(
-                Level::WARN,
-                "Sensor name recieved from {} was not valid UTF-8",
-                socket
-            );
-            continue;
-        };
-        event!(Level::TRACE, "Read sensor name: {} from {}", name, socket);
 
-        // read counter
-        let mut counter: [u8; 5] = [0; 5];
-        for i in 0..5 {
-            let Ok(byte) = reader.read_u8().await else {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(FrameError::Closed) => {
+                event!(Level::INFO, "Connection closed by {}", socket);
+                return;
+            }
+            Err(e) if e.is_fatal() => {
                 event!(
                     Level::WARN,
-                    "failed to read counter. Closing connection: {}",
+                    "Failed to read frame: {}. Closing connection: {}",
+                    e,
                     socket
                 );
                 return;
+            }
+            Err(e) => {
+                event!(Level::WARN, "Dropping invalid frame from {}: {}", socket, e);
+                services.metrics.invalid_frame();
+                if !allowance.charge(PeerError::InvalidFrame) {
+                    allowance.log_exceeded(socket, PeerError::InvalidFrame);
+                    return;
+                }
+                continue;
+            }
+        };
+        event!(
+            Level::TRACE,
+            "Read {:?} frame for sensor {} from {}",
+            frame.format,
+            frame.name,
+            socket
+        );
+        services.metrics.frame_parsed(frame.format);
+
+        let status = handle_frame(&frame, socket, &services).await;
+
+        if frame.ack_requested() {
+            let ack = Ack {
+                counter: frame.counter,
+                status,
             };
-            counter[i] = byte;
+            if let Err(e) = send_ack(&mut writer, ack).await {
+                event!(
+                    Level::WARN,
+                    "Failed to acknowledge frame {} to {}: {}. Closing connection",
+                    frame.counter,
+                    socket,
+                    e
+                );
+                return;
+            }
+        }
+
+        let error = match status {
+            AckStatus::UnknownSensor => {
+                unknown_sensors.record(&frame.name, socket);
+                services.metrics.unknown_sensor_frame();
+                PeerError::UnknownSensor
+            }
+            AckStatus::DecryptFailure => PeerError::DecryptFailure,
+            _ => continue,
+        };
+        if !allowance.charge(error) {
+            allowance.log_exceeded(socket, error);
+            return;
         }
+    }
+}
 
-        // read encrypted packet size
-        let Ok(encrypted_packet_size) = reader.read_u8().await else {
+async fn send_ack<W: AsyncWrite + Unpin>(writer: &mut W, ack: Ack) -> io::Result<()> {
+    writer.write_all(&ack.encode()).await?;
+    writer.flush().await
+}
+
+/// Decrypts, checks and stores the reading of one frame.
+async fn handle_frame(frame: &Frame, socket: SocketAddr, services: &Services) -> AckStatus {
+    let Services {
+        sensors,
+        store,
+        replay,
+        live,
+        metrics,
+        ..
+    } = services;
+    let name = &frame.name;
+
+    let cipher: Aes128Ccm;
+    let nonce: GenericArray<u8, ccm::consts::U13>;
+
+    {
+        // read lock scope
+        let read_lock = sensors.read().await;
+        let Some(sensor) = read_lock.get(name) else {
             event!(
-                Level::WARN,
-                "failed to read encrypted packet size. Closing connection: {}",
+                Level::DEBUG,
+                "sensor \"{}\" is not a known sensor ({})",
+                name,
                 socket
             );
-            return;
+            return AckStatus::UnknownSensor;
         };
 
-        // read encrypted packet
-        let mut encrypted_packet: Vec<u8> = vec![0u8; encrypted_packet_size as usize];
-        let Ok(_) = reader.read_exact(&mut encrypted_packet).await else {
+        sensor.stats.received_frames.fetch_add(1, Ordering::Relaxed);
+        let Some(key) = sensor.key_for_counter(frame.counter) else {
             event!(
                 Level::WARN,
-                "failed to read encrypted packet. Closing connection: {}",
+                "frame {} from {} ({}) is past the sensor's last key interval",
+                frame.counter,
+                name,
                 socket
             );
-            return;
+            return AckStatus::DecryptFailure;
         };
 
-        let cipher: Aes128Ccm;
-        let nonce: GenericArray<u8, ccm::consts::U13>;
+        nonce = sensor.ccm_data.get_nonce(frame.counter);
+        cipher = Aes128Ccm::new(&key.into());
+    }
+    let decrypted_packet = cipher.decrypt(&nonce, frame.payload.as_slice());
 
-        {
-            // read lock scope
-            let read_lock = sensors.read().await;
-            let Some(sensor) = read_lock.get(&name) else {
-                event!(
-                    Level::WARN,
-                    "sensor \"{}\" is not a known sensor. Dropping connection: {}",
-                    name,
-                    socket
-                );
-                return;
-            };
+    metrics.decrypted(name, decrypted_packet.is_ok());
+    let bytes = match decrypted_packet {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            if let Some(sensor) = sensors.read().await.get(name) {
+                sensor
+                    .stats
+                    .decrypt_failures
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            event!(
+                Level::WARN,
+                "Failed to decypted packet from: {}. Error: {}",
+                socket,
+                e
+            );
+            return AckStatus::DecryptFailure;
+        }
+    };
 
-            nonce = sensor.ccm_data.get_nonce(counter);
-            cipher = Aes128Ccm::new_from_slice(&sensor.key).unwrap();
+    // only authenticated frames may move the replay window
+    if let Err(e) = replay::admit(replay, name, frame.counter).await {
+        if e == ReplayError::Unsaved {
+            return AckStatus::StorageFailure;
         }
-        let decrypted_packet = cipher.decrypt(&nonce, encrypted_packet.as_slice());
+        let rejected = match sensors.read().await.get(name) {
+            Some(sensor) => sensor.stats.replayed_frames.fetch_add(1, Ordering::Relaxed) + 1,
+            None => 0,
+        };
+        event!(
+            Level::WARN,
+            "Rejected frame {} from {} ({}): {}. {} frames rejected so far",
+            frame.counter,
+            name,
+            socket,
+            e,
+            rejected
+        );
+        return AckStatus::Replayed;
+    }
 
-        match decrypted_packet {
-            Ok(bytes) => {
-                event!(
-                    Level::INFO,
-                    "Recieved packet from {}: {}",
-                    name,
-                    String::from_utf8(bytes).unwrap()
-                )
-            }
+    let values = {
+        // read lock scope
+        let read_lock = sensors.read().await;
+        let Some(sensor) = read_lock.get(name) else {
+            event!(Level::WARN, "sensor {} was deregistered", name);
+            return AckStatus::UnknownSensor;
+        };
+        sensor
+            .stats
+            .last_seen
+            .store(now_millis(), Ordering::Relaxed);
+
+        match sensor.parse_reading(&bytes) {
+            Ok(values) => values,
             Err(e) => {
+                let rejected = sensor.stats.schema_errors.fetch_add(1, Ordering::Relaxed) + 1;
                 event!(
                     Level::WARN,
-                    "Failed to decypted packet from: {}. Error: {}",
-                    socket,
-                    e
+                    "Rejected packet {} from {}: {}. {} packets failed validation so far",
+                    frame.counter,
+                    name,
+                    e,
+                    rejected
                 );
+                return AckStatus::SchemaError;
             }
         }
+    };
+
+    let reading = Reading {
+        sensor: name.clone(),
+        counter: frame.counter,
+        received_at: now_millis(),
+        values,
+    };
+    let appended = storage::blocking(store, move |store| Ok((store.append(&reading)?, reading)));
+    match appended.await {
+        Ok((sequence, reading)) => {
+            event!(
+                Level::INFO,
+                "Stored reading {} from {}: {:?}",
+                sequence,
+                reading.sensor,
+                reading.values
+            );
+            // no live subscribers is not an error
+            let _ = live.send(Arc::new(StoredReading { sequence, reading }));
+            AckStatus::Accepted
+        }
+        Err(e) => {
+            event!(Level::ERROR, "Failed to store reading from {}: {}", name, e);
+            // the sender may retry, which must not count as a replay
+            replay.forget(name, frame.counter);
+            AckStatus::StorageFailure
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::frame::FLAG_ACK_REQUESTED;
+    use crate::key_schedule::{derive_key, KEY_SIZE};
+    use crate::storage::{
+        FieldValue, Order, ReadingQuery, SegmentLog, StoredReading, DEFAULT_SEGMENT_SIZE,
+    };
+    use crate::tls::{self, test_pki::TestPki};
+    use crate::FieldType;
+    use std::{path::Path, sync::atomic::AtomicU64, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const SEED: [u8; 260] = [3; 260];
+    const INITIAL_KEY: [u8; KEY_SIZE] = [5; KEY_SIZE];
+    const INTERVAL: u32 = 10;
+
+    // the key the firmware encrypts `counter` with
+    fn firmware_key(counter: u64) -> [u8; KEY_SIZE] {
+        match counter / INTERVAL as u64 {
+            0 => INITIAL_KEY,
+            interval_counter => derive_key(&SEED, interval_counter as u32),
+        }
+    }
+
+    fn test_sensor() -> Sensor {
+        let mut sensor = Sensor::new(
+            "testSensor".to_owned(),
+            SEED.to_vec(),
+            INITIAL_KEY,
+            [0; 8],
+            INTERVAL,
+        );
+        sensor.add_field("x".to_owned(), FieldType::Integer);
+        sensor.add_field("y".to_owned(), FieldType::Float);
+        sensor
+    }
+
+    fn encrypted_frame(sensor: &Sensor, counter: u64, plaintext: &str) -> Vec<u8> {
+        encrypt(sensor, counter, plaintext).encode()
+    }
+
+    fn encrypt(sensor: &Sensor, counter: u64, plaintext: &str) -> Frame {
+        let key = firmware_key(counter);
+        let cipher = Aes128Ccm::new(&key.into());
+        let nonce = sensor.ccm_data.get_nonce(counter);
+        let payload = cipher.encrypt(&nonce, plaintext.as_bytes()).unwrap();
+
+        Frame::new(sensor.name.clone(), counter, payload)
+    }
+
+    fn test_services(
+        dir: &Path,
+        sensors: Arc<RwLock<HashMap<String, Sensor>>>,
+        store: Arc<dyn ReadingStore>,
+    ) -> Services {
+        Services {
+            sensors,
+            store,
+            replay: Arc::new(ReplayGuard::open(dir.join("replay.json")).unwrap()),
+            live: broadcast::channel(16).0,
+            error_budget: DEFAULT_ERROR_BUDGET,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    async fn wait_for_readings(store: &SegmentLog, count: usize) -> Vec<StoredReading> {
+        let query = ReadingQuery {
+            sensor: "testSensor".to_owned(),
+            from: None,
+            to: None,
+            after: None,
+            limit: usize::MAX,
+            order: Order::Ascending,
+        };
+
+        for _ in 0..50 {
+            let readings = store.query(&query).unwrap();
+            if readings.len() >= count {
+                return readings;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        store.query(&query).unwrap()
+    }
+
+    #[tokio::test]
+    async fn happy_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+
+        let mut frames = Vec::new();
+        // cross two key rotations
+        for counter in 0..25 {
+            frames.extend(encrypted_frame(
+                &sensor,
+                counter,
+                &format!("{{\"x\": {}, \"y\": 0.5}}", counter),
+            ));
+        }
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8100").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            test_services(dir.path(), sensors.clone(), store.clone()),
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8100").await.unwrap();
+        stream.write_all(&frames).await.unwrap();
+
+        let readings = wait_for_readings(&store, 25).await;
+        assert_eq!(readings.len(), 25);
+        for (i, reading) in readings.iter().enumerate() {
+            assert_eq!(reading.reading.counter, i as u64);
+            assert_eq!(
+                reading.reading.values.get("x"),
+                Some(&FieldValue::Integer(i as i64))
+            );
+            assert_eq!(
+                reading.reading.values.get("y"),
+                Some(&FieldValue::Float(0.5))
+            );
+        }
+
+        let stats = &sensors.read().await["testSensor"].stats;
+        assert_eq!(stats.received_frames.load(Ordering::Relaxed), 25);
+        assert_eq!(stats.decrypt_failures.load(Ordering::Relaxed), 0);
+        assert_ne!(stats.last_seen.load(Ordering::Relaxed), 0);
+    }
+
     #[tokio::test]
-    async fn happy_path() {}
+    async fn rejects_replayed_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+
+        let mut frames = Vec::new();
+        for counter in [0, 1, 2, 1, 2, 3] {
+            frames.extend(encrypted_frame(&sensor, counter, "{\"x\": 1, \"y\": 2}"));
+        }
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let services = test_services(dir.path(), sensors.clone(), store.clone());
+        let replay = services.replay.clone();
+        let listener = TcpListener::bind("localhost:8101").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            services,
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8101").await.unwrap();
+        stream.write_all(&frames).await.unwrap();
+
+        let readings = wait_for_readings(&store, 4).await;
+        let counters: Vec<u64> = readings.iter().map(|r| r.reading.counter).collect();
+        assert_eq!(counters, vec![0, 1, 2, 3]);
+        assert_eq!(
+            sensors.read().await["testSensor"]
+                .stats
+                .replayed_frames
+                .load(Ordering::Relaxed),
+            2
+        );
+
+        // the flushed window is persisted, so a restarted server still rejects them
+        replay.flush().unwrap();
+        let replay = ReplayGuard::open(dir.path().join("replay.json")).unwrap();
+        assert!(replay.check_and_record("testSensor", 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_payloads_not_matching_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+
+        let payloads = [
+            "{\"x\": 1, \"y\": 2}",
+            "{\"x\": 1}",
+            "{\"x\": 1, \"y\": 2, \"z\": 3}",
+            "{\"x\": 1.5, \"y\": 2}",
+            "not json",
+            "{\"x\": 2, \"y\": 2.5}",
+        ];
+        let mut frames = Vec::new();
+        for (counter, payload) in payloads.iter().enumerate() {
+            frames.extend(encrypted_frame(&sensor, counter as u64, payload));
+        }
+        // invalid UTF-8
+        let key = firmware_key(6);
+        let payload = Aes128Ccm::new(&key.into())
+            .encrypt(&sensor.ccm_data.get_nonce(6), &[0xff, 0xfe][..])
+            .unwrap();
+        frames.extend(Frame::new(sensor.name.clone(), 6, payload).encode());
+        frames.extend(encrypted_frame(&sensor, 7, "{\"x\": 3, \"y\": 0}"));
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8102").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            test_services(dir.path(), sensors.clone(), store.clone()),
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8102").await.unwrap();
+        stream.write_all(&frames).await.unwrap();
+
+        let readings = wait_for_readings(&store, 3).await;
+        let counters: Vec<u64> = readings.iter().map(|r| r.reading.counter).collect();
+        assert_eq!(counters, vec![0, 5, 7]);
+        assert_eq!(
+            readings[1].reading.values.get("y"),
+            Some(&FieldValue::Float(2.5))
+        );
+        assert_eq!(
+            sensors.read().await["testSensor"]
+                .stats
+                .schema_errors
+                .load(Ordering::Relaxed),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn acknowledges_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+
+        let with_ack = |mut frame: Frame| {
+            frame.flags |= FLAG_ACK_REQUESTED;
+            frame.encode()
+        };
+        let mut frames = Vec::new();
+        frames.extend(with_ack(encrypt(&sensor, 0, "{\"x\": 1, \"y\": 2}")));
+        frames.extend(with_ack(encrypt(&sensor, 1, "{\"x\": 1}")));
+        frames.extend(with_ack(encrypt(&sensor, 0, "{\"x\": 1, \"y\": 2}")));
+        frames.extend(with_ack(Frame::new(sensor.name.clone(), 2, vec![0; 16])));
+        // no ack requested
+        frames.extend(encrypted_frame(&sensor, 3, "{\"x\": 3, \"y\": 2}"));
+        frames.extend(with_ack(Frame::new(
+            "unknownSensor".to_owned(),
+            4,
+            vec![0; 16],
+        )));
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8111").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            test_services(dir.path(), sensors, store.clone()),
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8111").await.unwrap();
+        stream.write_all(&frames).await.unwrap();
+
+        let mut response = [0; 5 * 6];
+        stream.read_exact(&mut response).await.unwrap();
+        let acks: Vec<Ack> = response
+            .chunks(6)
+            .map(|bytes| Ack::decode(bytes).unwrap())
+            .collect();
+        let expected = [
+            (0, AckStatus::Accepted),
+            (1, AckStatus::SchemaError),
+            (0, AckStatus::Replayed),
+            (2, AckStatus::DecryptFailure),
+            (4, AckStatus::UnknownSensor),
+        ];
+        assert_eq!(
+            acks,
+            expected.map(|(counter, status)| Ack { counter, status })
+        );
+
+        let readings = wait_for_readings(&store, 2).await;
+        let counters: Vec<u64> = readings.iter().map(|r| r.reading.counter).collect();
+        assert_eq!(counters, vec![0, 3]);
+    }
+
+    #[tokio::test]
+    async fn finishes_frame_in_flight_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+        let mut frame = encrypt(&sensor, 0, "{\"x\": 1, \"y\": 2}");
+        frame.flags |= FLAG_ACK_REQUESTED;
+        let frame = frame.encode();
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(false),
+            test_services(dir.path(), sensors, store.clone()),
+            shutdown.clone(),
+        ));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let (first, rest) = frame.split_at(frame.len() / 2);
+        stream.write_all(first).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.cancel();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream.write_all(rest).await.unwrap();
+
+        let mut response = [0; 6];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            Ack::decode(&response),
+            Some(Ack {
+                counter: 0,
+                status: AckStatus::Accepted
+            })
+        );
+        // and the connection is closed instead of waiting for another frame
+        assert_eq!(stream.read(&mut response).await.unwrap(), 0);
+        assert_eq!(wait_for_readings(&store, 1).await.len(), 1);
+    }
+
+    #[test]
+    fn error_allowance_counts_errors_by_kind() {
+        let mut allowance = ErrorAllowance::new(ErrorBudget {
+            burst: 3,
+            per_sec: 0,
+        });
+        assert!(allowance.charge(PeerError::InvalidFrame));
+        assert!(allowance.charge(PeerError::DecryptFailure));
+        assert!(allowance.charge(PeerError::DecryptFailure));
+        assert!(!allowance.charge(PeerError::InvalidFrame));
+
+        assert_eq!(allowance.invalid_frames, 2);
+        assert_eq!(allowance.unknown_sensor_frames, 0);
+        assert_eq!(allowance.decrypt_failures, 2);
+    }
+
+    /// Fails the first `failures` appends.
+    struct FailingStore {
+        failures: AtomicU64,
+        inner: SegmentLog,
+    }
+
+    impl ReadingStore for FailingStore {
+        fn append(&self, reading: &Reading) -> io::Result<u64> {
+            let failed = self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |f| f.checked_sub(1));
+            match failed {
+                Ok(_) => Err(io::Error::other("disk full")),
+                Err(_) => self.inner.append(reading),
+            }
+        }
+
+        fn query(&self, query: &ReadingQuery) -> io::Result<Vec<StoredReading>> {
+            self.inner.query(query)
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_after_storage_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(FailingStore {
+            failures: AtomicU64::new(1),
+            inner: SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap(),
+        });
+        let sensor = test_sensor();
+        let mut frame = encrypt(&sensor, 0, "{\"x\": 1, \"y\": 2}");
+        frame.flags |= FLAG_ACK_REQUESTED;
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8114").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            test_services(dir.path(), sensors, store.clone()),
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8114").await.unwrap();
+        let mut response = [0; 6];
+        for expected in [
+            AckStatus::StorageFailure,
+            AckStatus::Accepted,
+            AckStatus::Replayed,
+        ] {
+            stream.write_all(&frame.encode()).await.unwrap();
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(Ack::decode(&response).unwrap().status, expected);
+        }
+
+        let readings = wait_for_readings(&store.inner, 1).await;
+        assert_eq!(readings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_sensors() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+        let unknown = |counter| Frame::new("unknownSensor".to_owned(), counter, vec![0; 16]);
+
+        let mut frames = Vec::new();
+        for counter in 0..3 {
+            frames.extend(encrypted_frame(&sensor, counter, "{\"x\": 1, \"y\": 2}"));
+            for _ in 0..10 {
+                frames.extend(unknown(counter).encode());
+            }
+        }
+
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8112").await.unwrap();
+        let mut services = test_services(dir.path(), sensors, store.clone());
+        services.error_budget = ErrorBudget {
+            burst: 40,
+            per_sec: 0,
+        };
+        let metrics = services.metrics.clone();
+        tokio::spawn(serve(
+            listener,
+            None,
+            FrameCodec::new(true),
+            services,
+            CancellationToken::new(),
+        ));
+
+        let mut stream = TcpStream::connect("localhost:8112").await.unwrap();
+        stream.write_all(&frames).await.unwrap();
+
+        let readings = wait_for_readings(&store, 3).await;
+        let counters: Vec<u64> = readings.iter().map(|r| r.reading.counter).collect();
+        assert_eq!(counters, vec![0, 1, 2]);
+        let exported = metrics.encode();
+        for line in [
+            "project_server_data_connections_accepted_total 1",
+            "project_server_data_decrypt_successes_total{sensor=\"testSensor\"} 3",
+        ] {
+            assert!(exported.contains(line), "{} missing", line);
+        }
+
+        // a peer sending mostly junk uses up its budget
+        for counter in 3..20 {
+            let _ = stream.write_all(&unknown(counter).encode()).await;
+        }
+        let closed = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 1]))
+            .await
+            .unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn gateways_need_a_client_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(SegmentLog::open(dir.path(), DEFAULT_SEGMENT_SIZE).unwrap());
+        let sensor = test_sensor();
+        let pki = TestPki::generate();
+        let acceptor = tls::acceptor(
+            &pki.path("server.pem"),
+            &pki.path("server.key"),
+            Some(&pki.path("ca.pem")),
+        )
+        .unwrap();
+
+        let rogue_frame = encrypted_frame(&sensor, 0, "{\"x\": 0, \"y\": 0}");
+        let gateway_frame = encrypted_frame(&sensor, 1, "{\"x\": 1, \"y\": 0}");
+        let sensors = Arc::new(RwLock::new(HashMap::from([(sensor.name.clone(), sensor)])));
+        let listener = TcpListener::bind("localhost:8107").await.unwrap();
+        tokio::spawn(serve(
+            listener,
+            Some(acceptor),
+            FrameCodec::new(true),
+            test_services(dir.path(), sensors, store.clone()),
+            CancellationToken::new(),
+        ));
+
+        // plain TCP never completes a handshake
+        let mut stream = TcpStream::connect("localhost:8107").await.unwrap();
+        stream.write_all(&rogue_frame).await.unwrap();
+
+        // with TLS 1.3 a rejected certificate only surfaces on the next read
+        for client in [None, Some("rogue")] {
+            if let Ok(mut stream) = pki.connect("localhost:8107", client).await {
+                let _ = stream.write_all(&rogue_frame).await;
+                assert!(stream.read(&mut [0; 1]).await.is_err());
+            }
+        }
+
+        let mut stream = pki
+            .connect("localhost:8107", Some("gateway"))
+            .await
+            .unwrap();
+        stream.write_all(&gateway_frame).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let readings = wait_for_readings(&store, 1).await;
+        let counters: Vec<u64> = readings.iter().map(|r| r.reading.counter).collect();
+        assert_eq!(counters, vec![1]);
+    }
 }
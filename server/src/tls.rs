@@ -0,0 +1,248 @@
+//! Optional TLS for the HTTP API and the data port.
+//!
+//! Both listeners use the same certificate. The data port can additionally
+//! require clients, usually gateways, to present a certificate issued by a
+//! configured CA.
+
+use std::{
+    fs::File,
+    future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{event, Level};
+
+/// How long a client gets to complete its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Completed handshakes waiting to be accepted.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Loads the certificate chain and private key for a listener. With a
+/// `client_ca`, clients must present a certificate it issued.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(invalid_data)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(invalid_data)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_data(format!("no private key in {}", path.display())))
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Listener that completes handshakes in the background, so a slow or
+/// silent client doesn't hold up the connections behind it.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, handshaken) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_handshakes(listener, acceptor, sender));
+
+        Ok(TlsListener {
+            local_addr,
+            handshaken,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(connection) => connection,
+            // the handshake task only stops once this listener is dropped
+            None => future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept_handshakes(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshaken: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = handshaken.closed() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    event!(Level::ERROR, "TCP connection error: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let acceptor = acceptor.clone();
+        let handshaken = handshaken.clone();
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    // the listener was dropped if this fails
+                    let _ = handshaken.send((stream, addr)).await;
+                }
+                Ok(Err(e)) => event!(Level::WARN, "TLS handshake with {} failed: {}", addr, e),
+                Err(_) => event!(Level::WARN, "TLS handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+/// Certificates generated for tests.
+#[cfg(test)]
+pub mod test_pki {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore};
+    use tempfile::TempDir;
+    use tokio::net::TcpStream;
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    use super::{load_certs, load_key};
+
+    pub struct TestPki {
+        dir: TempDir,
+    }
+
+    impl TestPki {
+        /// A CA, a `localhost` server certificate and a client certificate
+        /// issued by the CA, plus a self-signed client certificate.
+        pub fn generate() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let write = |name: &str, contents: String| fs::write(dir.path().join(name), contents);
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            ca_params
+                .distinguished_name
+                .push(DnType::CommonName, "test CA");
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+            write("ca.pem", ca.pem()).unwrap();
+
+            let issue = |name: &str, purpose: ExtendedKeyUsagePurpose, self_signed: bool| {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+                params.distinguished_name.push(DnType::CommonName, name);
+                params.extended_key_usages = vec![purpose];
+                let cert = if self_signed {
+                    params.self_signed(&key).unwrap()
+                } else {
+                    params.signed_by(&key, &ca, &ca_key).unwrap()
+                };
+                write(&format!("{}.pem", name), cert.pem()).unwrap();
+                write(&format!("{}.key", name), key.serialize_pem()).unwrap();
+            };
+            issue("server", ExtendedKeyUsagePurpose::ServerAuth, false);
+            issue("gateway", ExtendedKeyUsagePurpose::ClientAuth, false);
+            issue("rogue", ExtendedKeyUsagePurpose::ClientAuth, true);
+
+            TestPki { dir }
+        }
+
+        pub fn path(&self, file: &str) -> PathBuf {
+            self.dir.path().join(file)
+        }
+
+        /// Connects to a server using this CA, presenting the certificate
+        /// named `client` if given.
+        pub async fn connect(
+            &self,
+            address: &str,
+            client: Option<&str>,
+        ) -> std::io::Result<TlsStream<TcpStream>> {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&self.path("ca.pem")).unwrap() {
+                roots.add(cert).unwrap();
+            }
+            let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match client {
+                Some(name) => builder
+                    .with_client_auth_cert(
+                        load_certs(&self.path(&format!("{}.pem", name))).unwrap(),
+                        load_key(&self.path(&format!("{}.key", name))).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+
+            let stream = TcpStream::connect(address).await?;
+            TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+        }
+    }
+}
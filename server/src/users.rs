@@ -0,0 +1,528 @@
+//! Authorized users and their roles.
+//!
+//! Every user has a PEM encoded public key in the user directory, named
+//! after the user. Roles are assigned in a manifest next to the keys:
+//!
+//! ```toml
+//! [alice]
+//! role = "admin"
+//!
+//! [bob]
+//! role = "operator"
+//! disabled = true
+//! ```
+//!
+//! Users missing from the manifest are enabled viewers. Changes to the
+//! directory are picked up while the server runs, see [`watch`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    future::Future,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use rsa::{
+    pkcs1v15::VerifyingKey,
+    pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding},
+    sha2::Sha256,
+    RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::RwLock,
+    time::MissedTickBehavior,
+};
+use tracing::{event, Level};
+
+/// File name of the role manifest inside the user directory.
+pub const ROLE_MANIFEST: &str = "roles.toml";
+/// How often the user directory is checked for changes.
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can manage users, rotate the server key and access every sensor.
+    Admin,
+    /// Can register sensors and access the sensors it owns.
+    Operator,
+    /// Can only authenticate.
+    #[default]
+    Viewer,
+}
+
+impl Role {
+    pub fn can_register(self) -> bool {
+        matches!(self, Role::Admin | Role::Operator)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Admin => write!(f, "admin"),
+            Role::Operator => write!(f, "operator"),
+            Role::Viewer => write!(f, "viewer"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthorizedUser {
+    pub key: VerifyingKey<Sha256>,
+    pub role: Role,
+    /// Disabled users keep their key and role but can't authenticate.
+    pub disabled: bool,
+}
+
+impl AuthorizedUser {
+    pub fn new(public_key: RsaPublicKey, role: Role) -> Self {
+        AuthorizedUser {
+            key: public_key.into(),
+            role,
+            disabled: false,
+        }
+    }
+
+    pub fn public_key(&self) -> &RsaPublicKey {
+        self.key.as_ref()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct ManifestEntry {
+    #[serde(default)]
+    role: Role,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    disabled: bool,
+}
+
+/// The user directory, holding one key file per user and the role manifest.
+#[derive(Clone)]
+pub struct UserStore {
+    dir: PathBuf,
+}
+
+impl UserStore {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        UserStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Loads every user key and assigns the roles from the manifest.
+    pub fn load(&self) -> io::Result<HashMap<String, AuthorizedUser>> {
+        let mut manifest = self.load_manifest()?;
+        let mut users = HashMap::new();
+
+        for path in self.key_files()? {
+            let user_filename = path.file_name().unwrap().to_str().unwrap();
+            let username = user_filename.split('.').next().unwrap();
+
+            // one bad key file shouldn't lock out every other user
+            let pub_key = match fs::read_to_string(&path) {
+                Ok(key_string) => match RsaPublicKey::from_public_key_pem(&key_string) {
+                    Ok(pub_key) => pub_key,
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            "Skipping malformed key {}: {}",
+                            user_filename,
+                            e
+                        );
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        "Skipping unreadable key {}: {}",
+                        user_filename,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let entry = manifest.remove(username).unwrap_or_default();
+            users.insert(
+                username.to_owned(),
+                AuthorizedUser {
+                    key: pub_key.into(),
+                    role: entry.role,
+                    disabled: entry.disabled,
+                },
+            );
+        }
+
+        for user in manifest.keys() {
+            event!(Level::WARN, "Role manifest names unknown user {}", user);
+        }
+
+        Ok(users)
+    }
+
+    /// Key files with their modification time and size, used to notice
+    /// changes to the directory.
+    pub fn fingerprint(&self) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let mut files = self.key_files()?;
+        files.push(self.dir.join(ROLE_MANIFEST));
+
+        let mut fingerprint = Vec::new();
+        for path in files {
+            match fs::metadata(&path) {
+                Ok(metadata) => fingerprint.push((path, metadata.modified()?, metadata.len())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        fingerprint.sort();
+
+        Ok(fingerprint)
+    }
+
+    /// Writes the key file of `user`, replacing any previous key.
+    pub fn save_key(&self, user: &str, public_key: &RsaPublicKey) -> io::Result<()> {
+        let pem = public_key
+            .to_public_key_pem(LineEnding::LF)
+            .map_err(io::Error::other)?;
+        write_atomic(&self.key_path(user), pem.as_bytes())
+    }
+
+    /// Deletes every key file of `user`.
+    pub fn remove_key(&self, user: &str) -> io::Result<()> {
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if file_name != ROLE_MANIFEST && file_name.split('.').next() == Some(user) {
+                fs::remove_file(dir_entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the manifest with the roles and flags of `users`.
+    pub fn save_manifest(&self, users: &HashMap<String, AuthorizedUser>) -> io::Result<()> {
+        let manifest: BTreeMap<&str, ManifestEntry> = users
+            .iter()
+            .map(|(name, user)| {
+                let entry = ManifestEntry {
+                    role: user.role,
+                    disabled: user.disabled,
+                };
+                (name.as_str(), entry)
+            })
+            .collect();
+        let manifest = toml::to_string(&manifest).map_err(io::Error::other)?;
+
+        write_atomic(&self.dir.join(ROLE_MANIFEST), manifest.as_bytes())
+    }
+
+    fn load_manifest(&self) -> io::Result<HashMap<String, ManifestEntry>> {
+        let path = self.dir.join(ROLE_MANIFEST);
+        match fs::read_to_string(&path) {
+            Ok(manifest) => toml::from_str(&manifest).map_err(invalid_data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                event!(
+                    Level::WARN,
+                    "No role manifest at {}, every user is a viewer",
+                    path.display()
+                );
+                Ok(HashMap::new())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every file in the directory that should hold a user key.
+    fn key_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = match dir_entry {
+                Ok(dir_entry) => dir_entry,
+                Err(e) => {
+                    event!(Level::ERROR, "Encountered error reading users: {}", e);
+                    continue;
+                }
+            };
+
+            let Ok(file_name) = dir_entry.file_name().into_string() else {
+                event!(Level::WARN, "Skipping user file with a non UTF-8 name");
+                continue;
+            };
+            // hidden files are partially written keys or manifests
+            if !dir_entry.file_type()?.is_file()
+                || file_name == ROLE_MANIFEST
+                || file_name.starts_with('.')
+            {
+                continue;
+            }
+
+            files.push(dir_entry.path());
+        }
+
+        Ok(files)
+    }
+
+    fn key_path(&self, user: &str) -> PathBuf {
+        self.dir.join(format!("{}.pem", user))
+    }
+}
+
+/// Reloads the users whenever the directory changes or the process gets a
+/// SIGHUP, swapping the whole map at once. A directory that fails to load
+/// leaves the current users in place.
+///
+/// The directory state is taken when this is called, so call it right after
+/// loading the users to not miss changes in between.
+pub fn watch(
+    store: UserStore,
+    users: Arc<RwLock<HashMap<String, AuthorizedUser>>>,
+    poll_interval: Duration,
+) -> impl Future<Output = ()> {
+    let mut last_fingerprint = store.fingerprint().ok();
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            event!(Level::ERROR, "Couldn't listen for SIGHUP: {}", e);
+            None
+        }
+    };
+
+    async move {
+        let mut interval = tokio::time::interval(poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let fingerprint = store.fingerprint().ok();
+                    if fingerprint == last_fingerprint {
+                        continue;
+                    }
+                    last_fingerprint = fingerprint;
+                    event!(Level::INFO, "User directory changed, reloading users");
+                }
+                Some(()) = async { hangup.as_mut()?.recv().await } => {
+                    event!(Level::INFO, "Received SIGHUP, reloading users");
+                }
+            }
+
+            match store.load() {
+                Ok(loaded) => {
+                    let mut users = users.write().await;
+                    log_changes(&users, &loaded);
+                    *users = loaded;
+                }
+                Err(e) => event!(
+                    Level::ERROR,
+                    "Failed to reload users, keeping current: {}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+fn log_changes(old: &HashMap<String, AuthorizedUser>, new: &HashMap<String, AuthorizedUser>) {
+    for (name, user) in new {
+        match old.get(name) {
+            None => event!(Level::INFO, "Added {} user {}", user.role, name),
+            Some(old_user) => {
+                if old_user.public_key() != user.public_key() {
+                    event!(Level::INFO, "Changed key of user {}", name);
+                }
+                if old_user.role != user.role || old_user.disabled != user.disabled {
+                    event!(
+                        Level::INFO,
+                        "Changed user {} to role {}, disabled: {}",
+                        name,
+                        user.role,
+                        user.disabled
+                    );
+                }
+            }
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        event!(Level::INFO, "Removed user {}", name);
+    }
+}
+
+/// User names end up in file names, so they are restricted to a safe set.
+pub fn is_valid_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // hidden, so a concurrent load doesn't pick it up
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    {
+        let mut tmp = fs::File::create(&tmp_path)?;
+        tmp.write_all(bytes)?;
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rsa::RsaPrivateKey;
+
+    fn test_key() -> RsaPublicKey {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        RsaPublicKey::from(&private_key)
+    }
+
+    #[test]
+    fn roles_from_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path());
+        for name in ["alice", "bob", "carol"] {
+            store.save_key(name, &test_key()).unwrap();
+        }
+        fs::write(
+            dir.path().join(ROLE_MANIFEST),
+            "[alice]\nrole = \"admin\"\n\n\
+             [bob]\nrole = \"operator\"\ndisabled = true\n\n\
+             [mallory]\nrole = \"admin\"\n",
+        )
+        .unwrap();
+
+        let users = store.load().unwrap();
+        assert_eq!(users.len(), 3);
+        assert_eq!(users["alice"].role, Role::Admin);
+        assert_eq!(users["bob"].role, Role::Operator);
+        assert!(users["bob"].disabled);
+        assert_eq!(users["carol"].role, Role::Viewer);
+        assert!(!users["carol"].disabled);
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path());
+        store.save_key("alice", &test_key()).unwrap();
+        fs::write(dir.path().join(ROLE_MANIFEST), "[alice]\nrole = \"root\"\n").unwrap();
+
+        let e = store.load().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn changes_survive_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path());
+        let key = test_key();
+
+        let mut users = HashMap::from([
+            (
+                "alice".to_owned(),
+                AuthorizedUser::new(key.clone(), Role::Admin),
+            ),
+            (
+                "bob".to_owned(),
+                AuthorizedUser::new(test_key(), Role::Operator),
+            ),
+        ]);
+        users.get_mut("bob").unwrap().disabled = true;
+        for (name, user) in &users {
+            store.save_key(name, user.public_key()).unwrap();
+        }
+        store.save_manifest(&users).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded["alice"].public_key(), &key);
+        assert_eq!(loaded["alice"].role, Role::Admin);
+        assert!(loaded["bob"].disabled);
+
+        users.remove("bob");
+        store.remove_key("bob").unwrap();
+        store.save_manifest(&users).unwrap();
+        assert_eq!(store.load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(is_valid_username("test_User-1"));
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username("../alice"));
+        assert!(!is_valid_username("alice.pem"));
+        assert!(!is_valid_username(ROLE_MANIFEST));
+    }
+
+    #[test]
+    fn malformed_keys_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path());
+        store.save_key("alice", &test_key()).unwrap();
+        fs::write(dir.path().join("bob.pem"), "not a key").unwrap();
+        fs::write(dir.path().join("carol.pem"), [0xff, 0xfe]).unwrap();
+
+        let users = store.load().unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(users.contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = UserStore::new(dir.path());
+        store.save_key("alice", &test_key()).unwrap();
+        store.save_key("bob", &test_key()).unwrap();
+        let users = Arc::new(RwLock::new(store.load().unwrap()));
+        tokio::spawn(watch(
+            store.clone(),
+            users.clone(),
+            Duration::from_millis(20),
+        ));
+
+        let new_key = test_key();
+        store.save_key("bob", &new_key).unwrap();
+        store.save_key("carol", &test_key()).unwrap();
+        store.remove_key("alice").unwrap();
+        fs::write(
+            dir.path().join(ROLE_MANIFEST),
+            "[carol]\nrole = \"admin\"\n",
+        )
+        .unwrap();
+
+        for _ in 0..100 {
+            // the manifest is written last
+            let users = users.read().await;
+            if users
+                .get("carol")
+                .is_some_and(|carol| carol.role == Role::Admin)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let users = users.read().await;
+        assert!(!users.contains_key("alice"));
+        assert_eq!(users["bob"].public_key(), &new_key);
+        assert_eq!(users["carol"].role, Role::Admin);
+    }
+}